@@ -0,0 +1,210 @@
+use crate::dns_message::{Answer, Question};
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+type CacheKey = (String, u16, u16);
+
+const DEFAULT_MAX_ENTRIES: usize = 10_000;
+
+/// Answer cache shared by all forwarding threads. Each entry has its own
+/// lock and the map itself is only write-locked to add or evict keys, so
+/// refreshing one entry never blocks serving another.
+pub struct Cache {
+    entries: RwLock<HashMap<CacheKey, Arc<RwLock<CacheEntry>>>>,
+    max_entries: usize,
+}
+
+struct CacheEntry {
+    answers: Vec<Answer>,
+    expires_at: Instant,
+}
+
+impl Cache {
+    pub fn new() -> Self {
+        Self::with_max_entries(DEFAULT_MAX_ENTRIES)
+    }
+
+    /// Cache holding at most `max_entries` keys. Once full, expired entries
+    /// are swept on insert, and if none have expired the one closest to
+    /// expiry makes room.
+    pub fn with_max_entries(max_entries: usize) -> Self {
+        Self {
+            entries: RwLock::new(HashMap::new()),
+            max_entries: max_entries.max(1),
+        }
+    }
+
+    pub fn get(&self, question: &Question) -> Option<Vec<Answer>> {
+        let key = Self::key(question);
+        let entry = self.entries.read().unwrap().get(&key)?.clone();
+        let entry = entry.read().unwrap();
+        let now = Instant::now();
+
+        if entry.expires_at <= now {
+            drop(entry);
+            self.evict_expired(&key, now);
+            return None;
+        }
+
+        let remaining: u32 = entry
+            .expires_at
+            .duration_since(now)
+            .as_secs()
+            .try_into()
+            .unwrap_or(u32::MAX);
+
+        let answers = entry
+            .answers
+            .iter()
+            .cloned()
+            .map(|answer| Answer {
+                time_to_live: remaining,
+                ..answer
+            })
+            .collect();
+
+        Some(answers)
+    }
+
+    pub fn insert(&self, question: &Question, answers: &[Answer]) {
+        let Some(ttl) = answers.iter().map(|a| a.time_to_live).min() else {
+            return;
+        };
+
+        let entry = CacheEntry {
+            answers: answers.to_vec(),
+            expires_at: Instant::now() + Duration::from_secs(ttl.into()),
+        };
+
+        let key = Self::key(question);
+
+        if let Some(existing) = self.entries.read().unwrap().get(&key) {
+            *existing.write().unwrap() = entry;
+            return;
+        }
+
+        let mut entries = self.entries.write().unwrap();
+
+        if !entries.contains_key(&key) && entries.len() >= self.max_entries {
+            self.make_room(&mut entries);
+        }
+
+        entries.insert(key, Arc::new(RwLock::new(entry)));
+    }
+
+    fn make_room(&self, entries: &mut HashMap<CacheKey, Arc<RwLock<CacheEntry>>>) {
+        let now = Instant::now();
+        entries.retain(|_, entry| entry.read().unwrap().expires_at > now);
+
+        if entries.len() < self.max_entries {
+            return;
+        }
+
+        let soonest = entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.read().unwrap().expires_at)
+            .map(|(key, _)| key.clone());
+
+        if let Some(key) = soonest {
+            entries.remove(&key);
+        }
+    }
+
+    fn evict_expired(&self, key: &CacheKey, now: Instant) {
+        let mut entries = self.entries.write().unwrap();
+
+        // The entry may have been refreshed since the read lock was released.
+        if entries
+            .get(key)
+            .is_some_and(|entry| entry.read().unwrap().expires_at <= now)
+        {
+            entries.remove(key);
+        }
+    }
+
+    fn key(question: &Question) -> CacheKey {
+        (
+            question.name.to_ascii_lowercase(),
+            question.record_type,
+            question.class,
+        )
+    }
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn question() -> Question {
+        Question::new("Example.com".to_owned(), 1, 1)
+    }
+
+    fn answer(ttl: u32) -> Answer {
+        Answer::new("example.com".to_owned(), 1, 1, ttl, 4, vec![1, 2, 3, 4])
+    }
+
+    #[test]
+    fn lookup_is_case_insensitive_and_counts_down_ttl() {
+        let cache = Cache::new();
+        cache.insert(&question(), &[answer(300)]);
+
+        let hit = cache
+            .get(&Question::new("example.COM".to_owned(), 1, 1))
+            .unwrap();
+
+        assert_eq!(hit.len(), 1);
+        assert!(hit[0].time_to_live <= 300);
+    }
+
+    #[test]
+    fn expired_entry_is_evicted_on_lookup() {
+        let cache = Cache::new();
+        cache.insert(&question(), &[answer(0)]);
+
+        assert!(cache.get(&question()).is_none());
+        assert!(cache.entries.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn full_cache_sweeps_expired_entries_before_evicting_live_ones() {
+        let cache = Cache::with_max_entries(2);
+        let expired = Question::new("expired.com".to_owned(), 1, 1);
+        let short = Question::new("short.com".to_owned(), 1, 1);
+        let long = Question::new("long.com".to_owned(), 1, 1);
+
+        cache.insert(&expired, &[answer(0)]);
+        cache.insert(&long, &[answer(300)]);
+        cache.insert(&short, &[answer(60)]);
+
+        assert_eq!(cache.entries.read().unwrap().len(), 2);
+        assert!(cache.get(&short).is_some());
+
+        cache.insert(&question(), &[answer(300)]);
+
+        assert_eq!(cache.entries.read().unwrap().len(), 2);
+        assert!(cache.get(&short).is_none());
+        assert!(cache.get(&long).is_some());
+        assert!(cache.get(&question()).is_some());
+    }
+
+    #[test]
+    fn read_is_not_blocked_while_other_entry_is_write_locked() {
+        let cache = Cache::new();
+        let other = Question::new("other.com".to_owned(), 1, 1);
+        cache.insert(&question(), &[answer(300)]);
+        cache.insert(&other, &[answer(300)]);
+
+        let entry = cache.entries.read().unwrap()[&Cache::key(&other)].clone();
+        let _refreshing = entry.write().unwrap();
+
+        assert!(cache.get(&question()).is_some());
+    }
+}
@@ -0,0 +1,97 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+#[derive(Copy, Clone)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+#[derive(Debug, Error)]
+pub enum CidrParseError {
+    #[error("invalid network address")]
+    InvalidAddress,
+    #[error("invalid prefix length")]
+    InvalidPrefixLength,
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.network, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = CidrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+
+        let network: IpAddr = addr.parse().map_err(|_| CidrParseError::InvalidAddress)?;
+        let max_prefix_len = if network.is_ipv4() { 32 } else { 128 };
+
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_prefix_len)
+                .ok_or(CidrParseError::InvalidPrefixLength)?,
+            None => max_prefix_len,
+        };
+
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(cidr: &str, addr: &str) -> bool {
+        cidr.parse::<Cidr>()
+            .unwrap()
+            .contains(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn contains_matches_range_boundaries() {
+        assert!(contains("192.168.1.0/24", "192.168.1.0"));
+        assert!(contains("192.168.1.0/24", "192.168.1.255"));
+        assert!(!contains("192.168.1.0/24", "192.168.2.0"));
+        assert!(!contains("192.168.1.0/24", "192.168.0.255"));
+        assert!(contains("0.0.0.0/0", "203.0.113.7"));
+        assert!(contains("10.0.0.1", "10.0.0.1"));
+        assert!(!contains("10.0.0.1/32", "10.0.0.2"));
+        assert!(contains("2001:db8::/32", "2001:db8:ffff::1"));
+        assert!(!contains("2001:db8::/32", "2001:db9::"));
+        assert!(!contains("0.0.0.0/0", "::1"));
+    }
+
+    #[test]
+    fn from_str_rejects_invalid_prefix_length() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+}
@@ -1,9 +1,24 @@
-﻿use std::str::from_utf8;
+﻿use crate::error::{DnsEncodeError, DnsParseError};
+use crate::id_generator::IdGenerator;
+
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+use std::str::from_utf8;
 
 pub struct DnsMessage {
     pub header: Header,
     pub questions: Vec<Question>,
     pub answers: Vec<Answer>,
+    pub authorities: Vec<Answer>,
+    pub additionals: Vec<Answer>,
+}
+
+pub struct DnsMessageBuilder {
+    header: Header,
+    questions: Vec<Question>,
+    answers: Vec<Answer>,
+    edns_payload_size: Option<u16>,
+    max_udp_payload: u16,
 }
 
 #[derive(Copy, Clone)]
@@ -46,12 +61,26 @@ pub enum ResponseCode {
     Refused,
 }
 
+impl ResponseCode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResponseCode::NoError => "NOERROR",
+            ResponseCode::FormatError => "FORMERR",
+            ResponseCode::ServerFailure => "SERVFAIL",
+            ResponseCode::NameError => "NXDOMAIN",
+            ResponseCode::NotImplemented => "NOTIMP",
+            ResponseCode::Refused => "REFUSED",
+        }
+    }
+}
+
 pub struct Question {
     pub name: String,
     pub record_type: u16,
     pub class: u16,
 }
 
+#[derive(Clone)]
 pub struct Answer {
     pub name: String,
     pub record_type: u16,
@@ -61,8 +90,31 @@ pub struct Answer {
     pub data: Vec<u8>,
 }
 
+#[derive(Copy, Clone, Default)]
+pub struct ParseOptions {
+    pub allow_forward_pointers: bool,
+}
+
 trait Serializable {
-    fn serialize(&self) -> Vec<u8>;
+    fn serialize(&self, pos: usize, names: &mut CompressionMap) -> Result<Vec<u8>, DnsEncodeError>;
+}
+
+pub const UDP_MAX_SIZE: usize = 512;
+
+pub const TYPE_OPT: u16 = 41;
+
+const MAX_LABEL_LEN: usize = 63;
+const MAX_NAME_LEN: usize = 255;
+
+/// Largest offset a 14-bit compression pointer can reference.
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+
+/// Compression pointers followed within one name before it is treated as a
+/// pointer loop.
+const MAX_POINTER_JUMPS: usize = 128;
+
+struct CompressionMap {
+    offsets: HashMap<String, usize>,
 }
 
 impl DnsMessage {
@@ -71,74 +123,329 @@ impl DnsMessage {
             header,
             questions,
             answers,
+            authorities: Vec::new(),
+            additionals: Vec::new(),
         }
     }
 
-    pub fn deserialize(buf: &[u8; 512]) -> Self {
-        let header = Header::deserialize(buf);
-        let (questions, curr_pos) = Question::deserialize_questions(buf, &header.qd_count);
-        let answers = Answer::deserialize_answers(buf, &header.an_count, curr_pos);
+    pub fn new_query(id_generator: &dyn IdGenerator, questions: Vec<Question>) -> Self {
+        let header = Header {
+            packet_id: id_generator.next_id(),
+            qr_ind: QueryResponseIndicator::Query,
+            op_code: OperationCode::Query,
+            is_auth_ans: false,
+            is_trunc: false,
+            is_rec_desired: true,
+            is_rec_available: false,
+            r_code: ResponseCode::NoError,
+            qd_count: questions.len().try_into().unwrap(),
+            an_count: 0,
+            ns_count: 0,
+            ar_count: 0,
+        };
 
-        Self {
+        Self::new(header, questions, Vec::new())
+    }
+
+    /// Starts a response to `query`, echoing its ID, opcode and RD flag, and
+    /// answering its EDNS0 OPT record, if any, with one of our own.
+    pub fn response_for(query: &DnsMessage) -> DnsMessageBuilder {
+        let header = Header {
+            packet_id: query.header.packet_id,
+            qr_ind: QueryResponseIndicator::Response,
+            op_code: query.header.op_code,
+            is_auth_ans: false,
+            is_trunc: false,
+            is_rec_desired: query.header.is_rec_desired,
+            is_rec_available: false,
+            r_code: match query.header.op_code {
+                OperationCode::Query => ResponseCode::NoError,
+                _ => ResponseCode::NotImplemented,
+            },
+            qd_count: 0,
+            an_count: 0,
+            ns_count: 0,
+            ar_count: 0,
+        };
+
+        DnsMessageBuilder {
+            header,
+            questions: Vec::new(),
+            answers: Vec::new(),
+            edns_payload_size: query.edns_payload_size(),
+            max_udp_payload: UDP_MAX_SIZE as u16,
+        }
+    }
+
+    pub fn question(&self) -> Option<&Question> {
+        self.questions.first()
+    }
+
+    pub fn answers_by_question(&self) -> Vec<(&Question, Vec<&Answer>)> {
+        self.questions
+            .iter()
+            .map(|q| {
+                let answers = self
+                    .answers
+                    .iter()
+                    .filter(|a| {
+                        a.name.eq_ignore_ascii_case(&q.name)
+                            && a.record_type == q.record_type
+                            && a.class == q.class
+                    })
+                    .collect();
+
+                (q, answers)
+            })
+            .collect()
+    }
+
+    /// UDP payload size advertised by an EDNS0 OPT record, if present.
+    pub fn edns_payload_size(&self) -> Option<u16> {
+        self.additionals
+            .iter()
+            .find(|record| record.record_type == TYPE_OPT)
+            .map(|opt| opt.class)
+    }
+
+    pub fn is_query(&self) -> bool {
+        matches!(self.header.qr_ind, QueryResponseIndicator::Query)
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.header.is_response()
+    }
+
+    pub fn deserialize(buf: &[u8]) -> Result<Self, DnsParseError> {
+        Self::deserialize_with(buf, ParseOptions::default())
+    }
+
+    pub fn deserialize_with(buf: &[u8], options: ParseOptions) -> Result<Self, DnsParseError> {
+        let header = Header::deserialize(buf)?;
+        let (questions, curr_pos) =
+            Question::deserialize_questions(buf, &header.qd_count, options)?;
+        let (answers, curr_pos) =
+            Answer::deserialize_answers(buf, &header.an_count, curr_pos, options)?;
+        let (authorities, curr_pos) =
+            Answer::deserialize_answers(buf, &header.ns_count, curr_pos, options)?;
+        let (additionals, _) =
+            Answer::deserialize_answers(buf, &header.ar_count, curr_pos, options)?;
+
+        Ok(Self {
             header,
             questions,
             answers,
+            authorities,
+            additionals,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DnsEncodeError> {
+        Ok(self.to_bytes_truncated(usize::MAX)?.0)
+    }
+
+    /// Serializes the message, dropping trailing answers that would push it
+    /// past `max_len` bytes. Returns the bytes and whether anything was
+    /// dropped, in which case the TC bit is set in the serialized header.
+    pub fn to_bytes_truncated(&self, max_len: usize) -> Result<(Vec<u8>, bool), DnsEncodeError> {
+        let mut names = CompressionMap::new();
+        let mut msg = vec![0; 12];
+
+        for question in &self.questions {
+            let serialized = question.serialize(msg.len(), &mut names)?;
+            msg.extend(serialized);
         }
+
+        // Room is kept for the additional records, sized without compression
+        // as an upper bound, so that e.g. an OPT record survives truncation.
+        let additionals_len = self
+            .additionals
+            .iter()
+            .map(|record| Ok(record.serialize(0, &mut CompressionMap::new())?.len()))
+            .sum::<Result<usize, DnsEncodeError>>()?;
+        let records_max_len = max_len.saturating_sub(additionals_len);
+
+        let an_written = Self::write_records(&self.answers, &mut msg, &mut names, records_max_len)?;
+        let ns_written = match an_written == self.answers.len() {
+            true => Self::write_records(&self.authorities, &mut msg, &mut names, records_max_len)?,
+            false => 0,
+        };
+
+        for record in &self.additionals {
+            let serialized = record.serialize(msg.len(), &mut names)?;
+            msg.extend(serialized);
+        }
+
+        let is_truncated = an_written < self.answers.len() || ns_written < self.authorities.len();
+
+        let mut header = self.header;
+        header.fix_counts(
+            &self.questions,
+            &self.answers[..an_written],
+            &self.authorities[..ns_written],
+            &self.additionals,
+        );
+        header.is_trunc |= is_truncated;
+        msg[..12].copy_from_slice(&header.serialize(0, &mut names)?);
+
+        Ok((msg, is_truncated))
     }
 
-    pub fn serialize(&self) -> [u8; 512] {
-        let mut msg: [u8; 512] = [0; 512];
+    /// Updates the header counts to match the sections of this message.
+    pub fn fix_counts(&mut self) {
+        self.header.fix_counts(
+            &self.questions,
+            &self.answers,
+            &self.authorities,
+            &self.additionals,
+        );
+    }
 
-        let pos = 12;
-        msg[..pos].copy_from_slice(&self.header.serialize());
+    fn write_records(
+        records: &[Answer],
+        msg: &mut Vec<u8>,
+        names: &mut CompressionMap,
+        max_len: usize,
+    ) -> Result<usize, DnsEncodeError> {
+        let mut written = 0;
 
-        let questions_iter = self.questions.iter().map(|item| item as &dyn Serializable);
-        let (pos, msg) = Self::copy_from_iter(questions_iter, pos, msg);
+        for record in records {
+            let serialized = record.serialize(msg.len(), names)?;
 
-        let answers_iter = self.answers.iter().map(|item| item as &dyn Serializable);
-        let (_, msg) = Self::copy_from_iter(answers_iter, pos, msg);
+            if msg.len() + serialized.len() > max_len {
+                names.offsets.retain(|_, offset| *offset < msg.len());
+                break;
+            }
 
-        msg
+            msg.extend(serialized);
+            written += 1;
+        }
+
+        Ok(written)
     }
+}
 
-    fn copy_from_iter<'a>(
-        iter: impl Iterator<Item = &'a dyn Serializable>,
-        start_pos: usize,
-        msg: [u8; 512],
-    ) -> (usize, [u8; 512]) {
-        iter.fold((start_pos, msg), |mut acc, elem| {
-            let serialized = elem.serialize();
-            let begin = acc.0;
-            let end = begin + serialized.len();
-            acc.1[begin..end].copy_from_slice(&serialized);
-            (end, acc.1)
-        })
+impl DnsMessageBuilder {
+    pub fn authoritative(mut self, is_auth_ans: bool) -> Self {
+        self.header.is_auth_ans = is_auth_ans;
+        self
+    }
+
+    pub fn recursion_available(mut self, is_rec_available: bool) -> Self {
+        self.header.is_rec_available = is_rec_available;
+        self
+    }
+
+    pub fn r_code(mut self, r_code: ResponseCode) -> Self {
+        self.header.r_code = r_code;
+        self
+    }
+
+    /// Largest UDP payload size the response's OPT record may advertise.
+    pub fn max_udp_payload(mut self, size: u16) -> Self {
+        self.max_udp_payload = size;
+        self
+    }
+
+    pub fn questions(mut self, questions: Vec<Question>) -> Self {
+        self.questions = questions;
+        self
+    }
+
+    pub fn answers(mut self, answers: Vec<Answer>) -> Self {
+        self.answers = answers;
+        self
+    }
+
+    pub fn build(self) -> DnsMessage {
+        let mut msg = DnsMessage::new(self.header, self.questions, self.answers);
+
+        if let Some(size) = self.edns_payload_size {
+            let min = UDP_MAX_SIZE as u16;
+            let size = size.clamp(min, self.max_udp_payload.max(min));
+            msg.additionals
+                .push(Answer::new(String::new(), TYPE_OPT, size, 0, 0, Vec::new()));
+        }
+
+        msg.fix_counts();
+        msg
     }
 }
 
 impl Header {
-    fn deserialize(buf: &[u8; 512]) -> Self {
-        Self {
-            packet_id: u16::from_be_bytes(
-                buf[..2]
-                    .try_into()
-                    .expect("Failed to deserialize packet_id."),
+    pub fn is_response(&self) -> bool {
+        matches!(self.qr_ind, QueryResponseIndicator::Response)
+    }
+
+    fn fix_counts(
+        &mut self,
+        questions: &[Question],
+        answers: &[Answer],
+        authorities: &[Answer],
+        additionals: &[Answer],
+    ) {
+        self.qd_count = questions.len().try_into().unwrap();
+        self.an_count = answers.len().try_into().unwrap();
+        self.ns_count = authorities.len().try_into().unwrap();
+        self.ar_count = additionals.len().try_into().unwrap();
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, DnsParseError> {
+        Self::deserialize(buf)
+    }
+
+    pub fn flags_string(&self) -> String {
+        let flags: Vec<&str> = [
+            (
+                matches!(self.qr_ind, QueryResponseIndicator::Response),
+                "qr",
             ),
+            (self.is_auth_ans, "aa"),
+            (self.is_trunc, "tc"),
+            (self.is_rec_desired, "rd"),
+            (self.is_rec_available, "ra"),
+        ]
+        .into_iter()
+        .filter_map(|(is_set, flag)| is_set.then_some(flag))
+        .collect();
+
+        let op_code = match self.op_code {
+            OperationCode::Query => "QUERY".to_owned(),
+            OperationCode::IQuery => "IQUERY".to_owned(),
+            OperationCode::Status => "STATUS".to_owned(),
+            OperationCode::Other(v) => format!("OPCODE{}", v),
+        };
+
+        format!(
+            "opcode: {}, status: {}, flags: {}",
+            op_code,
+            self.r_code.name(),
+            flags.join(" ")
+        )
+    }
+
+    fn deserialize(buf: &[u8]) -> Result<Self, DnsParseError> {
+        if buf.len() < 12 {
+            return Err(DnsParseError::Truncated);
+        }
+
+        Ok(Self {
+            packet_id: u16::from_be_bytes(buf[..2].try_into().unwrap()),
             qr_ind: Self::deserialize_qr_ind(buf),
             op_code: Self::deserialize_op_code(buf),
             is_auth_ans: get_bit_flag_for_byte(buf, 2, 2),
             is_trunc: get_bit_flag_for_byte(buf, 2, 1),
             is_rec_desired: get_bit_flag_for_byte(buf, 2, 0),
             is_rec_available: get_bit_flag_for_byte(buf, 3, 7),
-            r_code: Self::deserialize_r_code(buf),
+            r_code: Self::deserialize_r_code(buf)?,
             qd_count: u16::from_be_bytes(buf[4..6].try_into().unwrap()),
             an_count: u16::from_be_bytes(buf[6..8].try_into().unwrap()),
             ns_count: u16::from_be_bytes(buf[8..10].try_into().unwrap()),
             ar_count: u16::from_be_bytes(buf[10..12].try_into().unwrap()),
-        }
+        })
     }
 
-    fn deserialize_qr_ind(buf: &[u8; 512]) -> QueryResponseIndicator {
+    fn deserialize_qr_ind(buf: &[u8]) -> QueryResponseIndicator {
         match (buf[2] >> 7) & 1 == 1 {
             false => QueryResponseIndicator::Query,
             true => QueryResponseIndicator::Response,
@@ -152,7 +459,7 @@ impl Header {
         }) << 7
     }
 
-    fn deserialize_op_code(buf: &[u8; 512]) -> OperationCode {
+    fn deserialize_op_code(buf: &[u8]) -> OperationCode {
         match (buf[2] >> 3) & 0xF {
             0 => OperationCode::Query,
             1 => OperationCode::IQuery,
@@ -170,15 +477,15 @@ impl Header {
         }) << 3
     }
 
-    fn deserialize_r_code(buf: &[u8; 512]) -> ResponseCode {
+    fn deserialize_r_code(buf: &[u8]) -> Result<ResponseCode, DnsParseError> {
         match buf[3] & 0xF {
-            0 => ResponseCode::NoError,
-            1 => ResponseCode::FormatError,
-            2 => ResponseCode::ServerFailure,
-            3 => ResponseCode::NameError,
-            4 => ResponseCode::NotImplemented,
-            5 => ResponseCode::Refused,
-            _ => panic!("Unexpected RCODE value"),
+            0 => Ok(ResponseCode::NoError),
+            1 => Ok(ResponseCode::FormatError),
+            2 => Ok(ResponseCode::ServerFailure),
+            3 => Ok(ResponseCode::NameError),
+            4 => Ok(ResponseCode::NotImplemented),
+            5 => Ok(ResponseCode::Refused),
+            v => Err(DnsParseError::UnexpectedRCode(v)),
         }
     }
 
@@ -195,7 +502,11 @@ impl Header {
 }
 
 impl Serializable for Header {
-    fn serialize(&self) -> Vec<u8> {
+    fn serialize(
+        &self,
+        _pos: usize,
+        _names: &mut CompressionMap,
+    ) -> Result<Vec<u8>, DnsEncodeError> {
         let mut header: [u8; 12] = [0; 12];
 
         header[..2].copy_from_slice(&self.packet_id.to_be_bytes());
@@ -216,7 +527,7 @@ impl Serializable for Header {
         header[8..10].copy_from_slice(&self.ns_count.to_be_bytes());
         header[10..12].copy_from_slice(&self.ar_count.to_be_bytes());
 
-        header.to_vec()
+        Ok(header.to_vec())
     }
 }
 
@@ -229,48 +540,64 @@ impl Question {
         }
     }
 
-    fn deserialize(raw: &[u8], pos: usize) -> (Self, usize) {
-        let (name, mut pos) = deserialize_name(raw, pos);
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DnsEncodeError> {
+        self.serialize(0, &mut CompressionMap::new())
+    }
+
+    pub fn from_bytes(raw: &[u8], offset: usize) -> Result<(Self, usize), DnsParseError> {
+        Self::deserialize(raw, offset, ParseOptions::default())
+    }
+
+    fn deserialize(
+        raw: &[u8],
+        pos: usize,
+        options: ParseOptions,
+    ) -> Result<(Self, usize), DnsParseError> {
+        let (name, mut pos) = deserialize_name_with(raw, pos, options)?;
 
-        let record_type = u16::from_be_bytes([raw[pos], raw[pos + 1]]);
+        let record_type = read_u16(raw, pos)?;
         pos += 2;
 
-        let class = u16::from_be_bytes([raw[pos], raw[pos + 1]]);
+        let class = read_u16(raw, pos)?;
         pos += 2;
 
-        (
+        Ok((
             Question {
                 name,
                 record_type,
                 class,
             },
             pos,
-        )
+        ))
     }
 
-    fn deserialize_questions(raw: &[u8], qd_count: &u16) -> (Vec<Self>, usize) {
+    fn deserialize_questions(
+        raw: &[u8],
+        qd_count: &u16,
+        options: ParseOptions,
+    ) -> Result<(Vec<Self>, usize), DnsParseError> {
         let mut questions = Vec::new();
 
         let mut curr_q_start = 12;
 
         for _ in 0..*qd_count {
-            let (q, next_q_start) = Self::deserialize(raw, curr_q_start);
+            let (q, next_q_start) = Self::deserialize(raw, curr_q_start, options)?;
             questions.push(q);
             curr_q_start = next_q_start;
         }
 
-        (questions, curr_q_start)
+        Ok((questions, curr_q_start))
     }
 }
 
 impl Serializable for Question {
-    fn serialize(&self) -> Vec<u8> {
-        let mut serialized: Vec<u8> = serialize_name(&self.name);
+    fn serialize(&self, pos: usize, names: &mut CompressionMap) -> Result<Vec<u8>, DnsEncodeError> {
+        let mut serialized: Vec<u8> = names.serialize_name(&self.name, pos)?;
 
         serialized.extend_from_slice(&self.record_type.to_be_bytes());
         serialized.extend_from_slice(&self.class.to_be_bytes());
 
-        serialized
+        Ok(serialized)
     }
 }
 
@@ -293,46 +620,147 @@ impl Answer {
         }
     }
 
-    fn deserialize_answers(raw: &[u8], an_count: &u16, pos: usize) -> Vec<Self> {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DnsEncodeError> {
+        self.serialize(0, &mut CompressionMap::new())
+    }
+
+    pub fn from_bytes(raw: &[u8], offset: usize) -> Result<(Self, usize), DnsParseError> {
+        Self::deserialize(raw, offset, ParseOptions::default())
+    }
+
+    pub fn as_cname(&self) -> Option<String> {
+        match self.record_type {
+            5 => self.rdata_name(),
+            _ => None,
+        }
+    }
+
+    pub fn as_ns(&self) -> Option<String> {
+        match self.record_type {
+            2 => self.rdata_name(),
+            _ => None,
+        }
+    }
+
+    pub fn as_ptr(&self) -> Option<String> {
+        match self.record_type {
+            12 => self.rdata_name(),
+            _ => None,
+        }
+    }
+
+    pub fn as_mx(&self) -> Option<(u16, String)> {
+        match self.record_type {
+            15 => Some((read_u16(&self.data, 0).ok()?, self.rdata_name()?)),
+            _ => None,
+        }
+    }
+
+    pub fn as_txt(&self) -> Option<Vec<String>> {
+        match self.record_type {
+            16 => decode_character_strings(&self.data).ok(),
+            _ => None,
+        }
+    }
+
+    fn rdata_name(&self) -> Option<String> {
+        let offset = rdata_name_offset(self.record_type)?;
+
+        deserialize_name(&self.data, offset)
+            .ok()
+            .map(|(name, _)| name)
+    }
+
+    fn deserialize_answers(
+        raw: &[u8],
+        an_count: &u16,
+        pos: usize,
+        options: ParseOptions,
+    ) -> Result<(Vec<Self>, usize), DnsParseError> {
         let mut answers = Vec::new();
 
         let mut curr_pos = pos;
 
         for _ in 0..*an_count {
-            let (a, next_pos) = Self::deserialize(raw, curr_pos);
+            let (a, next_pos) = Self::deserialize(raw, curr_pos, options)?;
             answers.push(a);
             curr_pos = next_pos;
         }
 
-        answers
+        Ok((answers, curr_pos))
     }
 
-    fn deserialize(raw: &[u8], pos: usize) -> (Self, usize) {
-        let (name, mut pos) = deserialize_name(raw, pos);
+    fn deserialize(
+        raw: &[u8],
+        pos: usize,
+        options: ParseOptions,
+    ) -> Result<(Self, usize), DnsParseError> {
+        let (name, mut pos) = deserialize_name_with(raw, pos, options)?;
 
-        let record_type = u16::from_be_bytes([raw[pos], raw[pos + 1]]);
+        let record_type = read_u16(raw, pos)?;
         pos += 2;
 
-        let class = u16::from_be_bytes([raw[pos], raw[pos + 1]]);
+        let class = read_u16(raw, pos)?;
         pos += 2;
 
-        let time_to_live = u32::from_be_bytes(raw[pos..pos + 4].try_into().unwrap());
+        let time_to_live = read_u32(raw, pos)?;
         pos += 4;
 
-        let length = u16::from_be_bytes([raw[pos], raw[pos + 1]]);
+        let length = read_u16(raw, pos)?;
         pos += 2;
 
-        let mut data = Vec::new();
-        if record_type == 1 && class == 1 {
-            for _ in 0..4 {
-                data.push(raw[pos]);
-                pos += 1;
+        let data = match (record_type, class) {
+            (1, 1) | (28, 1) => {
+                let expected = if record_type == 1 { 4 } else { 16 };
+                if length != expected {
+                    return Err(DnsParseError::InvalidRdataLength(record_type, length));
+                }
+                read_rdata(raw, pos, expected.into())?
             }
-        } else {
-            panic!("RR TYPE different than 'A' and CLASS different than 'IN' are not supported.")
-        }
+            (TYPE_OPT, _) => read_rdata(raw, pos, length as usize)?,
+            (6, 1) => {
+                read_rdata(raw, pos, length as usize)?;
+
+                let (mname, next_pos) = deserialize_name_with(raw, pos, options)?;
+                let (rname, next_pos) = deserialize_name_with(raw, next_pos, options)?;
+
+                // Names may be compressed, so RDLENGTH is checked against
+                // where they end on the wire rather than their decoded length.
+                if next_pos + 20 != pos + length as usize {
+                    return Err(DnsParseError::InvalidRdataLength(record_type, length));
+                }
+
+                let mut data = encode_name(&mname)?;
+                data.extend(encode_name(&rname)?);
+                data.extend(read_rdata(raw, next_pos, 20)?);
+                data
+            }
+            (16, 1) => {
+                let data = read_rdata(raw, pos, length as usize)?;
+                decode_character_strings(&data)?;
+                data
+            }
+            (record_type, 1) if rdata_name_offset(record_type).is_some() => {
+                let offset = rdata_name_offset(record_type).unwrap();
+                read_rdata(raw, pos, length as usize)?;
+                if offset > length as usize {
+                    return Err(DnsParseError::InvalidRdataLength(record_type, length));
+                }
+
+                let mut data = read_rdata(raw, pos, offset)?;
+                let (target, end_pos) = deserialize_name_with(raw, pos + offset, options)?;
+                if end_pos != pos + length as usize {
+                    return Err(DnsParseError::InvalidRdataLength(record_type, length));
+                }
+                data.extend(encode_name(&target)?);
+                data
+            }
+            // Unknown types are kept as opaque RDATA (RFC 3597).
+            _ => read_rdata(raw, pos, length as usize)?,
+        };
+        pos += length as usize;
 
-        (
+        Ok((
             Self {
                 name,
                 record_type,
@@ -342,66 +770,183 @@ impl Answer {
                 data,
             },
             pos,
-        )
+        ))
     }
 }
 
 impl Serializable for Answer {
-    fn serialize(&self) -> Vec<u8> {
-        let mut serialized: Vec<u8> = serialize_name(&self.name);
+    fn serialize(&self, pos: usize, names: &mut CompressionMap) -> Result<Vec<u8>, DnsEncodeError> {
+        let mut serialized: Vec<u8> = names.serialize_name(&self.name, pos)?;
 
         serialized.extend_from_slice(&self.record_type.to_be_bytes());
         serialized.extend_from_slice(&self.class.to_be_bytes());
         serialized.extend_from_slice(&self.time_to_live.to_be_bytes());
-        serialized.extend_from_slice(&self.length.to_be_bytes());
-        serialized.extend_from_slice(&self.data);
 
-        serialized
+        match (rdata_name_offset(self.record_type), self.rdata_name()) {
+            (Some(offset), Some(target)) => {
+                let rdata_pos = pos + serialized.len() + 2;
+                let mut rdata = self.data[..offset].to_vec();
+                rdata.extend(names.serialize_name(&target, rdata_pos + offset)?);
+
+                let length: u16 = rdata.len().try_into().unwrap();
+                serialized.extend_from_slice(&length.to_be_bytes());
+                serialized.extend(rdata);
+            }
+            _ => {
+                let length: u16 = self.data.len().try_into().unwrap();
+                serialized.extend_from_slice(&length.to_be_bytes());
+                serialized.extend_from_slice(&self.data);
+            }
+        }
+
+        Ok(serialized)
     }
 }
 
-fn serialize_name(input: &str) -> Vec<u8> {
-    input
-        .split('.')
-        .map(|label| {
-            let label_len: u8 = label
-                .len()
-                .try_into()
-                .expect("domain name part length exceeded");
-
-            let chars_encoded = label
-                .chars()
-                .map(|c| {
-                    let mut c_buf = vec![0; c.len_utf8()];
-                    c.encode_utf8(&mut c_buf);
-                    c_buf
-                })
-                .flatten();
+/// Offset of the domain name inside the RDATA of record types whose RDATA
+/// ends in a (compressible) name.
+fn rdata_name_offset(record_type: u16) -> Option<usize> {
+    match record_type {
+        2 | 5 | 12 => Some(0),
+        15 => Some(2),
+        _ => None,
+    }
+}
 
-            [label_len].into_iter().chain(chars_encoded)
-        })
-        .flatten()
-        .chain(vec![0u8; 1].into_iter())
+/// Encodes each string as one or more length-prefixed character-strings,
+/// splitting strings longer than 255 bytes.
+pub fn encode_character_strings(strings: &[&str]) -> Vec<u8> {
+    strings
+        .iter()
+        .flat_map(|string| string.as_bytes().chunks(255))
+        .flat_map(|chunk| [chunk.len() as u8].into_iter().chain(chunk.iter().copied()))
         .collect()
 }
 
-fn deserialize_name(raw: &[u8], pos: usize) -> (String, usize) {
+fn decode_character_strings(rdata: &[u8]) -> Result<Vec<String>, DnsParseError> {
+    let mut strings = Vec::new();
+    let mut pos = 0;
+
+    while let Some(&len) = rdata.get(pos) {
+        let begin = pos + 1;
+        let end = begin + len as usize;
+
+        let string = rdata.get(begin..end).ok_or(DnsParseError::Truncated)?;
+        strings.push(String::from_utf8_lossy(string).into_owned());
+
+        pos = end;
+    }
+
+    Ok(strings)
+}
+
+pub fn encode_name(name: &str) -> Result<Vec<u8>, DnsEncodeError> {
+    CompressionMap::new().serialize_name(name, 0)
+}
+
+pub fn ipv6_reverse_name(addr: &Ipv6Addr) -> String {
+    let nibbles: Vec<String> = addr
+        .octets()
+        .iter()
+        .rev()
+        .flat_map(|octet| [octet & 0xF, octet >> 4])
+        .map(|nibble| format!("{:x}", nibble))
+        .collect();
+
+    format!("{}.ip6.arpa", nibbles.join("."))
+}
+
+impl CompressionMap {
+    fn new() -> Self {
+        Self {
+            offsets: HashMap::new(),
+        }
+    }
+
+    fn serialize_name(&mut self, name: &str, pos: usize) -> Result<Vec<u8>, DnsEncodeError> {
+        let labels: Vec<&str> = name.split('.').filter(|l| !l.is_empty()).collect();
+        let mut serialized = Vec::new();
+
+        let name_len = labels.iter().map(|label| label.len() + 1).sum::<usize>() + 1;
+        if name_len > MAX_NAME_LEN {
+            return Err(DnsEncodeError::NameTooLong(name_len));
+        }
+
+        for (idx, label) in labels.iter().enumerate() {
+            let suffix = labels[idx..].join(".").to_ascii_lowercase();
+
+            if let Some(offset) = self.offsets.get(&suffix) {
+                let pointer = 0xC000 | *offset as u16;
+                serialized.extend_from_slice(&pointer.to_be_bytes());
+                return Ok(serialized);
+            }
+
+            let offset = pos + serialized.len();
+            if offset <= MAX_POINTER_OFFSET {
+                self.offsets.insert(suffix, offset);
+            }
+            serialized.extend(serialize_label(label)?);
+        }
+
+        serialized.push(0);
+        Ok(serialized)
+    }
+}
+
+fn serialize_label(label: &str) -> Result<Vec<u8>, DnsEncodeError> {
+    if label.len() > MAX_LABEL_LEN {
+        return Err(DnsEncodeError::LabelTooLong(label.len()));
+    }
+
+    Ok([label.len() as u8]
+        .into_iter()
+        .chain(label.bytes())
+        .collect())
+}
+
+fn deserialize_name(raw: &[u8], pos: usize) -> Result<(String, usize), DnsParseError> {
+    deserialize_name_with(raw, pos, ParseOptions::default())
+}
+
+fn deserialize_name_with(
+    raw: &[u8],
+    pos: usize,
+    options: ParseOptions,
+) -> Result<(String, usize), DnsParseError> {
     let mut state = NameDeserializeState::new(pos);
 
     loop {
-        match raw[state.pos] {
+        match *raw.get(state.pos).ok_or(DnsParseError::Truncated)? {
             0 => {
                 let end_pos = match state.jumped_to_offset {
                     true => state.end_pos,
                     false => state.pos + 1,
                 };
 
-                return (state.labels.join("."), end_pos);
+                return Ok((state.labels.join("."), end_pos));
             }
             v if v & 0xC0 == 0xC0 => {
+                let offset = (read_u16(raw, state.pos)? & 0x3FFF) as usize;
+
+                if offset >= raw.len() {
+                    return Err(DnsParseError::InvalidOffsetPointer(offset));
+                }
+
+                // Pointers must lead strictly backwards from where the current
+                // run of labels started, which also rules out pointer loops.
+                if offset >= state.segment_start && !options.allow_forward_pointers {
+                    return Err(DnsParseError::ForwardPointer(offset));
+                }
+
+                // Forward pointers may be allowed, so loops are also capped.
+                if state.jumps == MAX_POINTER_JUMPS {
+                    return Err(DnsParseError::PointerLoop(offset));
+                }
+
                 state = NameDeserializeState {
-                    pos: (u16::from_be_bytes(raw[state.pos..state.pos + 2].try_into().unwrap())
-                        & 0x3FFF) as usize,
+                    pos: offset,
+                    segment_start: offset,
+                    jumps: state.jumps + 1,
                     end_pos: match state.jumped_to_offset {
                         true => state.end_pos,
                         false => state.pos + 2,
@@ -412,21 +957,18 @@ fn deserialize_name(raw: &[u8], pos: usize) -> (String, usize) {
 
                 continue;
             }
+            v if v & 0xC0 != 0 => return Err(DnsParseError::ReservedLabelType(v & 0xC0)),
             v => {
                 let len = v as usize;
                 let begin = state.pos + 1;
                 let end = begin + len;
 
-                let label = from_utf8(&raw[begin..end])
-                    .expect("Sequence of bytes is not a valid UTF-8 string");
+                let label = raw.get(begin..end).ok_or(DnsParseError::Truncated)?;
+                let label = from_utf8(label).map_err(|_| DnsParseError::InvalidUtf8Label)?;
 
                 state.labels.push(label);
 
-                state = NameDeserializeState {
-                    pos: end,
-                    end_pos: end,
-                    ..state
-                };
+                state = NameDeserializeState { pos: end, ..state };
             }
         }
     }
@@ -434,8 +976,10 @@ fn deserialize_name(raw: &[u8], pos: usize) -> (String, usize) {
 
 struct NameDeserializeState<'a> {
     pos: usize,
+    segment_start: usize,
     end_pos: usize,
     jumped_to_offset: bool,
+    jumps: usize,
     labels: Vec<&'a str>,
 }
 
@@ -443,14 +987,34 @@ impl<'a> NameDeserializeState<'a> {
     fn new(pos: usize) -> Self {
         NameDeserializeState {
             pos,
+            segment_start: pos,
             end_pos: pos,
             jumped_to_offset: false,
+            jumps: 0,
             labels: Vec::new(),
         }
     }
 }
 
-fn get_bit_flag_for_byte(buf: &[u8; 512], byte_idx: usize, bit_idx: u8) -> bool {
+fn read_u16(raw: &[u8], pos: usize) -> Result<u16, DnsParseError> {
+    raw.get(pos..pos + 2)
+        .map(|bytes| u16::from_be_bytes(bytes.try_into().unwrap()))
+        .ok_or(DnsParseError::Truncated)
+}
+
+fn read_u32(raw: &[u8], pos: usize) -> Result<u32, DnsParseError> {
+    raw.get(pos..pos + 4)
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+        .ok_or(DnsParseError::Truncated)
+}
+
+fn read_rdata(raw: &[u8], pos: usize, len: usize) -> Result<Vec<u8>, DnsParseError> {
+    raw.get(pos..pos + len)
+        .map(|rdata| rdata.to_vec())
+        .ok_or(DnsParseError::Truncated)
+}
+
+fn get_bit_flag_for_byte(buf: &[u8], byte_idx: usize, bit_idx: u8) -> bool {
     buf[byte_idx].get_bit_flag(bit_idx)
 }
 
@@ -486,14 +1050,741 @@ impl AsBitFlag for bool {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::id_generator::SequentialIdGenerator;
+
+    /// Encodes `answer` in a message and decodes it again, checking that the
+    /// decoded message re-encodes to the same bytes.
+    fn round_trip(answer: Answer) -> Answer {
+        let question = Question::new(answer.name.clone(), answer.record_type, answer.class);
+        let mut msg = DnsMessage::new_query(&SequentialIdGenerator::new(0), vec![question]);
+        msg.answers.push(answer);
+
+        let raw = msg.to_bytes().unwrap();
+        let mut parsed = DnsMessage::deserialize(&raw).unwrap();
+        assert_eq!(parsed.to_bytes().unwrap(), raw);
+
+        parsed.answers.remove(0)
+    }
 
     #[test]
     fn name_to_labels_parses_string() {
-        let result = serialize_name("github.com");
+        let result = CompressionMap::new()
+            .serialize_name("github.com", 0)
+            .unwrap();
 
         assert_eq!(
             result,
             [0x6, 0x67, 0x69, 0x74, 0x68, 0x75, 0x62, 0x3, 0x63, 0x6f, 0x6d, 0x0]
         );
     }
+
+    #[test]
+    fn serialize_name_rejects_label_over_63_bytes() {
+        let name = format!("{}.com", "a".repeat(64));
+
+        assert_eq!(
+            CompressionMap::new().serialize_name(&name, 0),
+            Err(DnsEncodeError::LabelTooLong(64))
+        );
+        assert!(encode_name(&format!("{}.com", "a".repeat(63))).is_ok());
+    }
+
+    #[test]
+    fn serialize_name_rejects_name_over_255_bytes() {
+        let name = vec!["a".repeat(63); 4].join(".");
+
+        assert_eq!(
+            CompressionMap::new().serialize_name(&name, 0),
+            Err(DnsEncodeError::NameTooLong(257))
+        );
+
+        let question = Question::new(name, 1, 1);
+        let msg = DnsMessage::new_query(&SequentialIdGenerator::new(0), vec![question]);
+        assert!(msg.to_bytes().is_err());
+    }
+
+    #[test]
+    fn names_past_pointer_range_are_not_compressed() {
+        let mut msg = DnsMessage::new_query(
+            &SequentialIdGenerator::new(0),
+            vec![Question::new("example.com".to_owned(), 16, 1)],
+        );
+        let txt = encode_character_strings(&["x".repeat(255).as_str()]);
+        for _ in 0..70 {
+            msg.answers.push(Answer::new(
+                "example.com".to_owned(),
+                16,
+                1,
+                60,
+                txt.len() as u16,
+                txt.clone(),
+            ));
+        }
+        for _ in 0..2 {
+            msg.answers.push(Answer::new(
+                "late.test".to_owned(),
+                1,
+                1,
+                60,
+                4,
+                vec![192, 0, 2, 1],
+            ));
+        }
+
+        let raw = msg.to_bytes().unwrap();
+        let late = encode_name("late.test").unwrap();
+        let full_names = raw.windows(late.len()).filter(|w| *w == late).count();
+
+        assert!(raw.len() > MAX_POINTER_OFFSET);
+        assert_eq!(full_names, 2);
+        let parsed = DnsMessage::deserialize(&raw).unwrap();
+        assert_eq!(parsed.answers[71].name, "late.test");
+    }
+
+    #[test]
+    fn question_round_trips_through_bytes() {
+        let question = Question::new("github.com".to_owned(), 1, 1);
+
+        let mut raw = vec![0xFF; 3];
+        raw.extend(question.to_bytes().unwrap());
+        let (parsed, end) = Question::from_bytes(&raw, 3).unwrap();
+
+        assert_eq!(end, raw.len());
+        assert_eq!(parsed.name, "github.com");
+        assert_eq!(parsed.record_type, 1);
+        assert_eq!(parsed.class, 1);
+    }
+
+    #[test]
+    fn answer_round_trips_through_bytes() {
+        let answer = Answer::new("github.com".to_owned(), 1, 1, 60, 4, vec![8, 8, 8, 8]);
+
+        let raw = answer.to_bytes().unwrap();
+        let (parsed, end) = Answer::from_bytes(&raw, 0).unwrap();
+
+        assert_eq!(end, raw.len());
+        assert_eq!(parsed.to_bytes().unwrap(), raw);
+        assert_eq!(parsed.time_to_live, 60);
+        assert_eq!(parsed.data, [8, 8, 8, 8]);
+    }
+
+    #[test]
+    fn deserialize_name_rejects_extended_label_type() {
+        assert_eq!(
+            deserialize_name(&[0x41, b'a', 0x0], 0),
+            Err(DnsParseError::ReservedLabelType(0x40))
+        );
+    }
+
+    #[test]
+    fn deserialize_name_rejects_unallocated_label_type() {
+        assert_eq!(
+            deserialize_name(&[0x81, b'a', 0x0], 0),
+            Err(DnsParseError::ReservedLabelType(0x80))
+        );
+    }
+
+    #[test]
+    fn question_returns_first_question() {
+        let id_generator = SequentialIdGenerator::new(0);
+        let questions = vec![
+            Question::new("a.com".to_owned(), 1, 1),
+            Question::new("b.com".to_owned(), 1, 1),
+        ];
+
+        let empty = DnsMessage::new_query(&id_generator, Vec::new());
+        let query = DnsMessage::new_query(&id_generator, questions);
+
+        assert!(empty.question().is_none());
+        assert_eq!(query.question().unwrap().name, "a.com");
+    }
+
+    #[test]
+    fn ipv6_reverse_name_expands_all_nibbles() {
+        let addr: Ipv6Addr = "2001:db8::1".parse().unwrap();
+
+        assert_eq!(
+            ipv6_reverse_name(&addr),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+    }
+
+    #[test]
+    fn is_query_and_is_response_follow_qr_indicator() {
+        let mut msg = DnsMessage::new_query(&SequentialIdGenerator::new(0), Vec::new());
+
+        assert!(msg.is_query());
+        assert!(!msg.is_response());
+
+        msg.header.qr_ind = QueryResponseIndicator::Response;
+
+        assert!(!msg.is_query());
+        assert!(msg.is_response());
+    }
+
+    #[test]
+    fn serialize_corrects_header_counts() {
+        let mut msg = DnsMessage::new_query(
+            &SequentialIdGenerator::new(0),
+            vec![Question::new("a.com".to_owned(), 1, 1)],
+        );
+        msg.answers.push(Answer::new(
+            "a.com".to_owned(),
+            1,
+            1,
+            60,
+            4,
+            vec![1, 2, 3, 4],
+        ));
+        msg.header.qd_count = 3;
+        msg.header.an_count = 0;
+        msg.header.ns_count = 2;
+        msg.header.ar_count = 1;
+
+        let serialized = msg.to_bytes().unwrap();
+
+        assert_eq!(serialized[4..12], [0, 1, 0, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn deserialize_ignores_trailing_bytes_after_last_record() {
+        let mut msg = DnsMessage::new_query(
+            &SequentialIdGenerator::new(0),
+            vec![Question::new("a.com".to_owned(), 1, 1)],
+        );
+        msg.answers.push(Answer::new(
+            "a.com".to_owned(),
+            1,
+            1,
+            60,
+            4,
+            vec![1, 2, 3, 4],
+        ));
+
+        let mut buf = msg.to_bytes().unwrap();
+        buf.extend([0xFF; 16]);
+
+        let parsed = DnsMessage::deserialize(&buf).unwrap();
+
+        assert_eq!(parsed.questions.len(), 1);
+        assert_eq!(parsed.answers.len(), 1);
+        assert_eq!(parsed.answers[0].data, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn answers_by_question_groups_matching_answers() {
+        let mut msg = DnsMessage::new_query(
+            &SequentialIdGenerator::new(0),
+            vec![
+                Question::new("a.com".to_owned(), 1, 1),
+                Question::new("b.com".to_owned(), 1, 1),
+            ],
+        );
+        msg.answers = vec![
+            Answer::new("b.com".to_owned(), 1, 1, 60, 4, vec![10, 0, 0, 2]),
+            Answer::new("A.com".to_owned(), 1, 1, 60, 4, vec![10, 0, 0, 1]),
+            Answer::new("b.com".to_owned(), 1, 1, 60, 4, vec![10, 0, 0, 3]),
+            Answer::new("a.com".to_owned(), 28, 1, 60, 16, vec![0; 16]),
+        ];
+
+        let grouped = msg.answers_by_question();
+
+        assert_eq!(grouped.len(), 2);
+        assert_eq!(grouped[0].0.name, "a.com");
+        assert_eq!(grouped[0].1.len(), 1);
+        assert_eq!(grouped[0].1[0].data, [10, 0, 0, 1]);
+        assert_eq!(grouped[1].0.name, "b.com");
+        assert_eq!(grouped[1].1.len(), 2);
+    }
+
+    #[test]
+    fn aaaa_answer_round_trips() {
+        let addr: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let answer = Answer::new("a.com".to_owned(), 28, 1, 60, 16, addr.octets().to_vec());
+
+        let raw = answer.to_bytes().unwrap();
+        let (parsed, end) = Answer::from_bytes(&raw, 0).unwrap();
+
+        assert_eq!(end, raw.len());
+        assert_eq!(parsed.record_type, 28);
+        assert_eq!(parsed.length, 16);
+        assert_eq!(parsed.data, addr.octets());
+        assert_eq!(parsed.to_bytes().unwrap(), raw);
+    }
+
+    #[test]
+    fn address_record_with_wrong_rdlength_is_rejected() {
+        let mut raw = Answer::new("a.com".to_owned(), 28, 1, 60, 4, vec![192, 0, 2, 1])
+            .to_bytes()
+            .unwrap();
+
+        assert_eq!(
+            Answer::from_bytes(&raw, 0).err(),
+            Some(DnsParseError::InvalidRdataLength(28, 4))
+        );
+
+        // Retype the record as A and grow its RDATA to 8 bytes.
+        raw[8] = 1;
+        raw[16] = 8;
+        raw.extend([0; 4]);
+
+        assert_eq!(
+            Answer::from_bytes(&raw, 0).err(),
+            Some(DnsParseError::InvalidRdataLength(1, 8))
+        );
+    }
+
+    /// Serializes `answer` and overwrites its RDLENGTH with `length`,
+    /// appending padding so a longer RDLENGTH is not rejected as truncated.
+    fn with_rdlength(answer: Answer, length: u16) -> Vec<u8> {
+        let mut raw = answer.to_bytes().unwrap();
+        let rdlength_pos = encode_name(&answer.name).unwrap().len() + 8;
+        let actual = raw.len() - rdlength_pos - 2;
+
+        raw[rdlength_pos..rdlength_pos + 2].copy_from_slice(&length.to_be_bytes());
+        raw.resize(raw.len() + (length as usize).saturating_sub(actual), 0);
+        raw
+    }
+
+    #[test]
+    fn soa_record_with_wrong_rdlength_is_rejected() {
+        let mut data = encode_name("ns.example.com").unwrap();
+        data.extend(encode_name("host.example.com").unwrap());
+        data.extend([0; 20]);
+        let length = data.len() as u16;
+        let soa = || Answer::new("example.com".to_owned(), 6, 1, 60, length, data.clone());
+
+        assert_eq!(
+            Answer::from_bytes(&with_rdlength(soa(), length - 1), 0).err(),
+            Some(DnsParseError::InvalidRdataLength(6, length - 1))
+        );
+        assert_eq!(
+            Answer::from_bytes(&with_rdlength(soa(), length + 2), 0).err(),
+            Some(DnsParseError::InvalidRdataLength(6, length + 2))
+        );
+    }
+
+    #[test]
+    fn name_record_is_not_read_past_its_rdlength() {
+        let target = encode_name("example.com").unwrap();
+        let cname = Answer::new(
+            "www.example.com".to_owned(),
+            5,
+            1,
+            60,
+            target.len() as u16,
+            target,
+        );
+        let mut mx_data = 10u16.to_be_bytes().to_vec();
+        mx_data.extend(encode_name("mail.example.com").unwrap());
+        let mx = Answer::new(
+            "example.com".to_owned(),
+            15,
+            1,
+            60,
+            mx_data.len() as u16,
+            mx_data,
+        );
+
+        assert_eq!(
+            Answer::from_bytes(&with_rdlength(cname, 0), 0).err(),
+            Some(DnsParseError::InvalidRdataLength(5, 0))
+        );
+        assert_eq!(
+            Answer::from_bytes(&with_rdlength(mx, 1), 0).err(),
+            Some(DnsParseError::InvalidRdataLength(15, 1))
+        );
+    }
+
+    #[test]
+    fn deserialize_returns_error_for_truncated_question() {
+        let query = DnsMessage::new_query(
+            &SequentialIdGenerator::new(0),
+            vec![Question::new("github.com".to_owned(), 1, 1)],
+        );
+        let raw = query.to_bytes().unwrap();
+
+        assert_eq!(
+            DnsMessage::deserialize(&raw[..18]).err(),
+            Some(DnsParseError::Truncated)
+        );
+        assert_eq!(
+            DnsMessage::deserialize(&raw[..raw.len() - 1]).err(),
+            Some(DnsParseError::Truncated)
+        );
+    }
+
+    #[test]
+    fn deserialize_returns_error_for_pointer_out_of_bounds() {
+        assert_eq!(
+            deserialize_name(&[0xC0, 0x10], 0),
+            Err(DnsParseError::InvalidOffsetPointer(0x10))
+        );
+    }
+
+    #[test]
+    fn deserialize_name_follows_backward_pointer() {
+        let raw = [0x1, b'a', 0x0, 0x1, b'b', 0xC0, 0x0];
+
+        assert_eq!(deserialize_name(&raw, 3), Ok(("b.a".to_owned(), 7)));
+    }
+
+    #[test]
+    fn deserialize_name_rejects_forward_pointer_by_default() {
+        let raw = [0x1, b'b', 0xC0, 0x4, 0x1, b'a', 0x0];
+        let lenient = ParseOptions {
+            allow_forward_pointers: true,
+        };
+
+        assert_eq!(
+            deserialize_name(&raw, 0),
+            Err(DnsParseError::ForwardPointer(4))
+        );
+        assert_eq!(
+            deserialize_name_with(&raw, 0, lenient),
+            Ok(("b.a".to_owned(), 4))
+        );
+    }
+
+    #[test]
+    fn deserialize_name_rejects_self_referential_pointer() {
+        let raw = [0x1, b'a', 0xC0, 0x2];
+        let lenient = ParseOptions {
+            allow_forward_pointers: true,
+        };
+
+        assert_eq!(
+            deserialize_name(&raw, 0),
+            Err(DnsParseError::ForwardPointer(2))
+        );
+        assert_eq!(
+            deserialize_name_with(&raw, 0, lenient),
+            Err(DnsParseError::PointerLoop(2))
+        );
+
+        let mut packet = vec![0x0, 0x1, 0x1, 0x0, 0x0, 0x1, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0];
+        packet.extend([0xC0, 0xC, 0x0, 0x1, 0x0, 0x1]);
+        assert_eq!(
+            DnsMessage::deserialize_with(&packet, lenient).err(),
+            Some(DnsParseError::PointerLoop(12))
+        );
+    }
+
+    #[test]
+    fn serialize_compresses_repeated_names_into_pointers() {
+        let mut msg = DnsMessage::new_query(
+            &SequentialIdGenerator::new(0),
+            vec![Question::new("a.com".to_owned(), 1, 1)],
+        );
+        msg.answers = vec![
+            Answer::new("a.com".to_owned(), 1, 1, 60, 4, vec![10, 0, 0, 1]),
+            Answer::new("a.com".to_owned(), 1, 1, 60, 4, vec![10, 0, 0, 2]),
+        ];
+
+        let raw = msg.to_bytes().unwrap();
+
+        assert_eq!(raw.len(), 12 + 11 + 16 + 16);
+        assert_eq!(raw[23..25], [0xC0, 0x0C]);
+        assert_eq!(raw[39..41], [0xC0, 0x0C]);
+
+        let parsed = DnsMessage::deserialize(&raw).unwrap();
+        assert_eq!(parsed.answers[1].name, "a.com");
+        assert_eq!(parsed.answers[1].data, [10, 0, 0, 2]);
+    }
+
+    #[test]
+    fn serialize_compresses_shared_suffix() {
+        let mut names = CompressionMap::new();
+
+        names.serialize_name("www.example.com", 12).unwrap();
+        let serialized = names.serialize_name("mail.example.com", 40).unwrap();
+
+        assert_eq!(serialized, [0x4, b'm', b'a', b'i', b'l', 0xC0, 0x10]);
+    }
+
+    #[test]
+    fn to_bytes_truncated_drops_answers_past_512_bytes_and_sets_tc() {
+        let mut msg = DnsMessage::new_query(
+            &SequentialIdGenerator::new(0),
+            vec![Question::new("example.com".to_owned(), 1, 1)],
+        );
+        msg.answers = (0..40)
+            .map(|i| Answer::new("example.com".to_owned(), 1, 1, 60, 4, vec![10, 0, 0, i]))
+            .collect();
+
+        let (serialized, is_truncated) = msg.to_bytes_truncated(UDP_MAX_SIZE).unwrap();
+
+        assert!(is_truncated);
+        assert!(serialized.len() <= UDP_MAX_SIZE);
+        assert_eq!(serialized[2] & 0b10, 0b10);
+
+        let parsed = DnsMessage::deserialize(&serialized).unwrap();
+        assert!(parsed.header.is_trunc);
+        assert!(parsed.answers.len() < 40);
+        assert_eq!(parsed.header.an_count as usize, parsed.answers.len());
+    }
+
+    #[test]
+    fn ns_answer_round_trips_target_name() {
+        let target = encode_name("ns1.example.com").unwrap();
+        let answer = Answer::new(
+            "example.com".to_owned(),
+            2,
+            1,
+            3600,
+            target.len() as u16,
+            target,
+        );
+
+        let parsed = round_trip(answer);
+
+        assert_eq!(parsed.as_ns().as_deref(), Some("ns1.example.com"));
+    }
+
+    #[test]
+    fn ptr_answer_round_trips_through_bytes() {
+        let target = encode_name("host.example.com").unwrap();
+        let answer = Answer::new(
+            "10.2.0.192.in-addr.arpa".to_owned(),
+            12,
+            1,
+            3600,
+            target.len() as u16,
+            target,
+        );
+
+        let parsed = round_trip(answer);
+
+        assert_eq!(parsed.as_ptr().as_deref(), Some("host.example.com"));
+    }
+
+    #[test]
+    fn unknown_hip_record_round_trips_as_raw_rdata() {
+        let mut rdata = vec![0x10, 0x2, 0x0, 0x4];
+        rdata.extend([0xAB; 16]);
+        rdata.extend([0x3, 0x1, 0x0, 0x1]);
+        rdata.extend(encode_name("rvs.example.com").unwrap());
+        let answer = Answer::new(
+            "www.example.com".to_owned(),
+            55,
+            1,
+            300,
+            rdata.len() as u16,
+            rdata.clone(),
+        );
+
+        let parsed = round_trip(answer);
+
+        assert_eq!(parsed.data, rdata);
+    }
+
+    #[test]
+    fn cname_answer_round_trips_target_name() {
+        let target = encode_name("example.com").unwrap();
+        let answer = Answer::new(
+            "www.example.com".to_owned(),
+            5,
+            1,
+            60,
+            target.len() as u16,
+            target,
+        );
+
+        let parsed = round_trip(answer);
+
+        assert_eq!(parsed.as_cname().as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn flags_string_lists_set_flags_with_opcode_and_rcode() {
+        let mut header = DnsMessage::new_query(&SequentialIdGenerator::new(0), Vec::new()).header;
+        header.qr_ind = QueryResponseIndicator::Response;
+        header.is_rec_available = true;
+
+        assert_eq!(
+            header.flags_string(),
+            "opcode: QUERY, status: NOERROR, flags: qr rd ra"
+        );
+    }
+
+    #[test]
+    fn mx_answer_round_trips_preference_and_exchange() {
+        let mut data = 10u16.to_be_bytes().to_vec();
+        data.extend(encode_name("mail.example.com").unwrap());
+        let answer = Answer::new("example.com".to_owned(), 15, 1, 60, data.len() as u16, data);
+
+        let parsed = round_trip(answer);
+
+        assert_eq!(parsed.as_mx(), Some((10, "mail.example.com".to_owned())));
+    }
+
+    #[test]
+    fn txt_answer_with_two_strings_round_trips() {
+        let data = encode_character_strings(&["v=spf1 -all", "token=abc"]);
+        let answer = Answer::new("example.com".to_owned(), 16, 1, 60, data.len() as u16, data);
+
+        let parsed = round_trip(answer);
+
+        assert_eq!(
+            parsed.as_txt(),
+            Some(vec!["v=spf1 -all".to_owned(), "token=abc".to_owned()])
+        );
+    }
+
+    #[test]
+    fn txt_answer_with_overrunning_string_is_rejected() {
+        let raw = [0x3, b'a', b'b'];
+
+        assert_eq!(
+            decode_character_strings(&raw),
+            Err(DnsParseError::Truncated)
+        );
+    }
+
+    #[test]
+    fn deserialize_reads_payload_size_from_opt_record() {
+        let msg = DnsMessage::new_query(
+            &SequentialIdGenerator::new(0),
+            vec![Question::new("example.com".to_owned(), 1, 1)],
+        );
+        let mut raw = msg.to_bytes().unwrap();
+        raw[11] = 1;
+        raw.extend([0x0, 0x0, 0x29, 0x10, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0]);
+
+        let parsed = DnsMessage::deserialize(&raw).unwrap();
+
+        assert_eq!(parsed.edns_payload_size(), Some(4096));
+        assert_eq!(parsed.to_bytes().unwrap(), raw);
+    }
+
+    #[test]
+    fn root_owned_opt_record_fields_are_read_after_bare_zero_name() {
+        let msg = DnsMessage::new_query(
+            &SequentialIdGenerator::new(0),
+            vec![Question::new("example.com".to_owned(), 1, 1)],
+        );
+        let mut raw = msg.to_bytes().unwrap();
+        let opt_pos = raw.len();
+        raw[11] = 1;
+        raw.extend([0x0, 0x0, 0x29, 0x4, 0xD0, 0x0, 0x0, 0x80, 0x0, 0x0, 0x4]);
+        raw.extend([0x0, 0xA, 0x0, 0x0]);
+
+        assert_eq!(
+            deserialize_name(&raw, opt_pos),
+            Ok((String::new(), opt_pos + 1))
+        );
+
+        let parsed = DnsMessage::deserialize(&raw).unwrap();
+        let opt = &parsed.additionals[0];
+
+        assert_eq!(opt.name, "");
+        assert_eq!(opt.record_type, TYPE_OPT);
+        assert_eq!(opt.class, 1232);
+        assert_eq!(opt.time_to_live, 0x8000);
+        assert_eq!(opt.data, [0x0, 0xA, 0x0, 0x0]);
+        assert_eq!(parsed.to_bytes().unwrap(), raw);
+    }
+
+    #[test]
+    fn response_for_answers_query_opt_record() {
+        let mut query = DnsMessage::new_query(
+            &SequentialIdGenerator::new(7),
+            vec![Question::new("example.com".to_owned(), 1, 1)],
+        );
+        query
+            .additionals
+            .push(Answer::new(String::new(), TYPE_OPT, 4096, 0, 0, Vec::new()));
+
+        let response = DnsMessage::response_for(&query)
+            .max_udp_payload(1232)
+            .questions(vec![Question::new("example.com".to_owned(), 1, 1)])
+            .build();
+        let plain = DnsMessage::response_for(&DnsMessage::new_query(
+            &SequentialIdGenerator::new(8),
+            Vec::new(),
+        ))
+        .build();
+
+        assert_eq!(response.header.packet_id, 7);
+        assert!(response.is_response());
+        assert!(response.header.is_rec_desired);
+        assert_eq!(response.header.qd_count, 1);
+        assert_eq!(response.header.ar_count, 1);
+        assert_eq!(response.edns_payload_size(), Some(1232));
+        assert_eq!(plain.edns_payload_size(), None);
+        assert_eq!(plain.header.ar_count, 0);
+    }
+
+    #[test]
+    fn edns_payload_size_is_absent_without_opt_record() {
+        let msg = DnsMessage::new_query(
+            &SequentialIdGenerator::new(0),
+            vec![Question::new("example.com".to_owned(), 1, 1)],
+        );
+
+        let parsed = DnsMessage::deserialize(&msg.to_bytes().unwrap()).unwrap();
+
+        assert_eq!(parsed.edns_payload_size(), None);
+    }
+
+    #[test]
+    fn authority_section_round_trips_with_ns_count() {
+        let mut soa = encode_name("ns.example.com").unwrap();
+        soa.extend(encode_name("admin.example.com").unwrap());
+        soa.extend([0; 20]);
+        let mut msg = DnsMessage::new_query(
+            &SequentialIdGenerator::new(0),
+            vec![Question::new("missing.example.com".to_owned(), 1, 1)],
+        );
+        msg.authorities.push(Answer::new(
+            "example.com".to_owned(),
+            6,
+            1,
+            300,
+            soa.len() as u16,
+            soa.clone(),
+        ));
+
+        let raw = msg.to_bytes().unwrap();
+        let parsed = DnsMessage::deserialize(&raw).unwrap();
+
+        assert_eq!(raw[8..10], [0, 1]);
+        assert_eq!(parsed.header.ns_count, 1);
+        assert_eq!(parsed.authorities[0].name, "example.com");
+        assert_eq!(parsed.authorities[0].data, soa);
+    }
+
+    #[test]
+    fn additional_glue_record_round_trips_with_ar_count() {
+        let mut msg = DnsMessage::new_query(
+            &SequentialIdGenerator::new(0),
+            vec![Question::new("example.com".to_owned(), 2, 1)],
+        );
+        let ns = encode_name("ns1.example.com").unwrap();
+        msg.answers.push(Answer::new(
+            "example.com".to_owned(),
+            2,
+            1,
+            3600,
+            ns.len() as u16,
+            ns,
+        ));
+        msg.additionals.push(Answer::new(
+            "ns1.example.com".to_owned(),
+            1,
+            1,
+            3600,
+            4,
+            vec![192, 0, 2, 1],
+        ));
+
+        let raw = msg.to_bytes().unwrap();
+        let parsed = DnsMessage::deserialize(&raw).unwrap();
+
+        assert_eq!(parsed.header.ar_count, 1);
+        assert_eq!(parsed.additionals[0].name, "ns1.example.com");
+        assert_eq!(parsed.additionals[0].data, [192, 0, 2, 1]);
+        assert_eq!(parsed.edns_payload_size(), None);
+        assert_eq!(parsed.to_bytes().unwrap(), raw);
+    }
 }
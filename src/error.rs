@@ -0,0 +1,59 @@
+use std::io;
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum DnsError {
+    #[error("upstream I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("malformed upstream reply: {0}")]
+    Parse(#[from] DnsParseError),
+    #[error("query cannot be encoded: {0}")]
+    Encode(#[from] DnsEncodeError),
+    #[error("upstream reply question {received:?} does not match {sent:?}")]
+    QuestionMismatch { sent: String, received: String },
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum DnsParseError {
+    #[error("message is truncated")]
+    Truncated,
+    #[error("label is not a valid UTF-8 string")]
+    InvalidUtf8Label,
+    #[error("compression pointer to invalid offset {0}")]
+    InvalidOffsetPointer(usize),
+    #[error("compression pointer to offset {0} does not point backwards")]
+    ForwardPointer(usize),
+    #[error("compression pointer to offset {0} exceeds the pointer limit")]
+    PointerLoop(usize),
+    #[error("label type {0:#04x} is reserved and not supported")]
+    ReservedLabelType(u8),
+    #[error("unexpected RCODE value {0}")]
+    UnexpectedRCode(u8),
+    #[error("RDLENGTH {1} is invalid for record type {0}")]
+    InvalidRdataLength(u16, u16),
+    #[error("invalid domain name: {0}")]
+    InvalidName(#[from] DnsEncodeError),
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum DnsEncodeError {
+    #[error("label of {0} bytes exceeds 63 bytes")]
+    LabelTooLong(usize),
+    #[error("name of {0} bytes exceeds 255 bytes")]
+    NameTooLong(usize),
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ConfigError {
+    #[error("missing value for {0}")]
+    MissingValue(&'static str),
+    #[error("invalid bind address {0:?}")]
+    InvalidBindAddress(String),
+    #[error("invalid CIDR {0:?}")]
+    InvalidCidr(String),
+    #[error("invalid value {value:?} for {flag}")]
+    InvalidValue { flag: &'static str, value: String },
+    #[error("invalid zone file {path:?}: {reason}")]
+    InvalidZone { path: String, reason: String },
+}
@@ -0,0 +1,46 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU16, Ordering};
+
+pub trait IdGenerator: Send + Sync {
+    fn next_id(&self) -> u16;
+}
+
+pub struct RandomIdGenerator;
+
+impl IdGenerator for RandomIdGenerator {
+    fn next_id(&self) -> u16 {
+        RandomState::new().build_hasher().finish() as u16
+    }
+}
+
+pub struct SequentialIdGenerator {
+    next: AtomicU16,
+}
+
+impl SequentialIdGenerator {
+    pub fn new(start: u16) -> Self {
+        Self {
+            next: AtomicU16::new(start),
+        }
+    }
+}
+
+impl IdGenerator for SequentialIdGenerator {
+    fn next_id(&self) -> u16 {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequential_generator_yields_consecutive_ids() {
+        let generator = SequentialIdGenerator::new(u16::MAX);
+
+        assert_eq!(generator.next_id(), u16::MAX);
+        assert_eq!(generator.next_id(), 0);
+    }
+}
@@ -1,170 +1,2645 @@
+pub mod cache;
+pub mod cidr;
 pub mod dns_message;
+pub mod error;
+pub mod id_generator;
+pub mod log;
+pub mod rng;
+pub mod tcp;
+pub mod upstream;
+pub mod zone;
 
+use crate::cache::Cache;
+use crate::cidr::Cidr;
 use crate::dns_message::*;
+use crate::error::{ConfigError, DnsEncodeError, DnsError};
+use crate::id_generator::{IdGenerator, RandomIdGenerator};
+use crate::log::{LogLevel, LogSink, Logger, StdioSink};
+use crate::rng::Rng;
+use crate::upstream::{UdpUpstream, Upstream};
+use crate::zone::Zone;
 
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::fs;
 use std::io;
-use std::net::{SocketAddr, UdpSocket};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub fn run_dns_server(config: &DnsServerConfig) -> Result<(), Box<dyn Error>> {
-    let udp_socket = UdpSocket::bind(&config.bind_addr)?;
-    let mut buf = [0; 512];
+    let udp_socket = UdpSocket::bind(config.bind_addr)?;
+    let tcp_listener = match config.tcp {
+        true => Some(TcpListener::bind(config.bind_addr)?),
+        false => None,
+    };
+
+    thread::scope(|scope| {
+        if let Some(listener) = tcp_listener {
+            scope.spawn(move || serve_tcp(listener, config));
+        }
+
+        serve_udp(&udp_socket, config)
+    })
+}
+
+const DEFAULT_WORKERS: usize = 4;
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// Reads datagrams on the calling thread and hands them to `config.workers`
+/// worker threads, so a slow upstream only holds up the worker waiting on it.
+fn serve_udp(udp_socket: &UdpSocket, config: &DnsServerConfig) -> Result<(), Box<dyn Error>> {
+    let (sender, receiver) = mpsc::channel::<Datagram>();
+    let receiver = Mutex::new(receiver);
+
+    thread::scope(|scope| {
+        for _ in 0..config.workers {
+            let socket = udp_socket.try_clone()?;
+            let receiver = &receiver;
+            scope.spawn(move || serve_udp_worker(&socket, receiver, config));
+        }
+
+        // Moved in so that returning closes the channel and stops the workers.
+        let sender = sender;
+        let mut buf = vec![0; u16::MAX as usize];
+
+        loop {
+            let (size, source) = udp_socket.recv_from(&mut buf)?;
+
+            config
+                .logger
+                .debug(format_args!("Received {} bytes from {}", size, source));
+
+            sender.send((buf[..size].to_vec(), source))?;
+        }
+    })
+}
 
+fn serve_udp_worker(
+    udp_socket: &UdpSocket,
+    receiver: &Mutex<mpsc::Receiver<Datagram>>,
+    config: &DnsServerConfig,
+) {
     loop {
-        let (size, source) = udp_socket.recv_from(&mut buf)?;
+        let Ok((packet, source)) = receiver.lock().unwrap().recv() else {
+            return;
+        };
 
-        println!("Received {} bytes from {}", size, source);
+        let response = handle_packet(&packet, source, config).and_then(|response| {
+            let max_len = response
+                .edns_payload_size()
+                .map_or(config.no_edns_udp_size, usize::from);
 
-        match &config.mode {
-            DnsServerMode::ForwardingServer(resolver_addr) => {
-                _ = handle_query_fwd(&buf, &udp_socket, source, &resolver_addr)?;
+            udp_response_bytes(&response, max_len, config)
+        });
+
+        if let Some(response) = response {
+            if let Err(err) = udp_socket.send_to(&response, source) {
+                config.logger.warn(format_args!(
+                    "Failed to send response to {}: {}",
+                    source, err
+                ));
             }
-            DnsServerMode::ResolvingServer => {
-                _ = resolve_query(&buf, &udp_socket, source)?;
+        }
+    }
+}
+
+fn udp_response_bytes(
+    response: &DnsMessage,
+    max_len: usize,
+    config: &DnsServerConfig,
+) -> Option<Vec<u8>> {
+    let serialized = match response.to_bytes_truncated(max_len) {
+        Ok((serialized, _)) => serialized,
+        Err(err) => return format_error_bytes(response, err, config),
+    };
+
+    if serialized.len() <= max_len {
+        return Some(serialized);
+    }
+
+    match config.oversized_action {
+        OversizedResponseAction::HeaderOnly => {
+            let header = Header {
+                is_trunc: true,
+                ..response.header
+            };
+            DnsMessage::new(header, Vec::new(), Vec::new())
+                .to_bytes()
+                .ok()
+        }
+        OversizedResponseAction::Drop => {
+            config.logger.warn(format_args!(
+                "Dropping response {} exceeding {} bytes after truncation",
+                response.header.packet_id, max_len
+            ));
+            None
+        }
+    }
+}
+
+/// Serializes a FORMERR reply in place of a response holding a name that
+/// cannot be encoded.
+fn format_error_bytes(
+    response: &DnsMessage,
+    err: DnsEncodeError,
+    config: &DnsServerConfig,
+) -> Option<Vec<u8>> {
+    config.logger.warn(format_args!(
+        "Answering FORMERR to {}: {}",
+        response.header.packet_id, err
+    ));
+
+    format_error(response.header).to_bytes().ok()
+}
+
+const MAX_TCP_CONNECTIONS: usize = 64;
+
+/// Serves every TCP connection on its own thread, so an idle client does not
+/// hold up the others. Connections beyond `MAX_TCP_CONNECTIONS` are closed
+/// straight away.
+fn serve_tcp(listener: TcpListener, config: &DnsServerConfig) {
+    let open_connections = AtomicUsize::new(0);
+
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    config
+                        .logger
+                        .warn(format_args!("TCP connection error: {}", err));
+                    continue;
+                }
+            };
+
+            if open_connections.fetch_add(1, Ordering::SeqCst) >= MAX_TCP_CONNECTIONS {
+                open_connections.fetch_sub(1, Ordering::SeqCst);
+                config.logger.warn(format_args!(
+                    "Closing TCP connection: {} connections already open",
+                    MAX_TCP_CONNECTIONS
+                ));
+                continue;
             }
+
+            let open_connections = &open_connections;
+            scope.spawn(move || {
+                if let Err(err) = handle_tcp_connection(stream, config) {
+                    config
+                        .logger
+                        .warn(format_args!("TCP connection error: {}", err));
+                }
+
+                open_connections.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+}
+
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const TCP_CONNECTION_LIFETIME: Duration = Duration::from_secs(60);
+const MAX_TCP_QUERIES: usize = 100;
+
+/// Answers queries until the client closes the connection, stays idle for
+/// `TCP_IDLE_TIMEOUT`, has sent `MAX_TCP_QUERIES` queries or has been
+/// connected for `TCP_CONNECTION_LIFETIME`.
+fn handle_tcp_connection(mut stream: TcpStream, config: &DnsServerConfig) -> Result<(), io::Error> {
+    let source = stream.peer_addr()?;
+    let deadline = Instant::now() + TCP_CONNECTION_LIFETIME;
+
+    for _ in 0..MAX_TCP_QUERIES {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        stream.set_read_timeout(Some(remaining.min(TCP_IDLE_TIMEOUT)))?;
+
+        let buf = match tcp::read_message(&mut stream) {
+            Ok(buf) => buf,
+            Err(err) if is_connection_closed(&err) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        config.logger.debug(format_args!(
+            "Received {} bytes from {} over TCP",
+            buf.len(),
+            source
+        ));
+
+        let response =
+            handle_packet(&buf, source, config).and_then(|response| match response.to_bytes() {
+                Ok(serialized) => Some(serialized),
+                Err(err) => format_error_bytes(&response, err, config),
+            });
+
+        if let Some(response) = response {
+            tcp::write_message(&mut stream, &response)?;
         }
     }
+
+    config.logger.debug(format_args!(
+        "Closing TCP connection from {} after reaching its limit",
+        source
+    ));
+
+    Ok(())
 }
 
-fn resolve_query(
-    buf: &[u8; 512],
-    udp_socket: &UdpSocket,
-    source: SocketAddr,
-) -> Result<usize, io::Error> {
-    let query = DnsMessage::deserialize(&buf);
-    let response = get_response(&query);
+fn is_connection_closed(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+fn handle_packet(buf: &[u8], source: SocketAddr, config: &DnsServerConfig) -> Option<DnsMessage> {
+    // Answering a response, even with FORMERR, could start a loop between
+    // two servers, so these are dropped before anything else.
+    if Header::from_bytes(buf).is_ok_and(|header| header.is_response()) {
+        config
+            .logger
+            .debug(format_args!("Dropping response packet from {}", source));
+        return None;
+    }
+
+    let query = match DnsMessage::deserialize_with(buf, config.parse_options) {
+        Ok(query) => query,
+        Err(err) => {
+            config
+                .logger
+                .warn(format_args!("Malformed packet from {}: {}", source, err));
+
+            return match Header::from_bytes(buf) {
+                Ok(header) if config.is_source_allowed(source.ip()) => Some(format_error(header)),
+                _ => None,
+            };
+        }
+    };
 
-    udp_socket.send_to(&response.serialize(), source)
+    if !config.is_source_allowed(source.ip()) {
+        return match config.denied_action {
+            DeniedQueryAction::Refuse => Some(refuse_query(query)),
+            DeniedQueryAction::Drop => None,
+        };
+    }
+
+    config.logger.debug(format_args!(
+        "Query {} from {}: {}",
+        query.header.packet_id,
+        source,
+        query.header.flags_string()
+    ));
+
+    // Each question is forwarded on its own thread, so a cap keeps one
+    // packet from fanning out into thousands of threads and upstream queries.
+    if query.questions.len() > MAX_QUESTIONS {
+        config.logger.warn(format_args!(
+            "Query {} from {} has {} questions, answering FORMERR",
+            query.header.packet_id,
+            source,
+            query.questions.len()
+        ));
+        return Some(format_error(query.header));
+    }
+
+    let started = Instant::now();
+
+    let (response, upstream_latency) = match &config.mode {
+        DnsServerMode::ForwardingServer(resolver_addr) => (
+            handle_query_fwd(query, resolver_addr, config),
+            Some(started.elapsed()),
+        ),
+        DnsServerMode::ResolvingServer => (resolve_query(query, config), None),
+    };
+
+    log_response(&response, source, upstream_latency, config);
+
+    Some(response)
 }
 
-fn handle_query_fwd(
-    buf: &[u8; 512],
-    udp_socket: &UdpSocket,
+fn log_response(
+    response: &DnsMessage,
     source: SocketAddr,
-    resolver_addr: &str,
-) -> Result<usize, io::Error> {
-    let query = DnsMessage::deserialize(&buf);
+    upstream_latency: Option<Duration>,
+    config: &DnsServerConfig,
+) {
+    let latency = match upstream_latency {
+        Some(latency) => format!(" upstream={}ms", latency.as_millis()),
+        None => String::new(),
+    };
+
+    for question in &response.questions {
+        config.logger.info(format_args!(
+            "{} {} type={} rcode={}{}",
+            source,
+            question.name,
+            question.record_type,
+            response.header.r_code.name(),
+            latency
+        ));
+    }
 
+    config.logger.debug(format_args!(
+        "Response {}: {}",
+        response.header.packet_id,
+        response.header.flags_string()
+    ));
+}
+
+fn refuse_query(query: DnsMessage) -> DnsMessage {
     let header = Header {
-        packet_id: query.header.packet_id,
         qr_ind: QueryResponseIndicator::Response,
-        op_code: query.header.op_code,
         is_auth_ans: false,
         is_trunc: false,
-        is_rec_desired: query.header.is_rec_desired,
         is_rec_available: false,
-        r_code: match query.header.op_code {
-            OperationCode::Query => ResponseCode::NoError,
-            _ => ResponseCode::NotImplemented,
-        },
-        qd_count: query.header.qd_count,
-        an_count: query.header.qd_count,
+        r_code: ResponseCode::Refused,
+        an_count: 0,
         ns_count: 0,
         ar_count: 0,
+        ..query.header
     };
 
-    let response = query
-        .questions
-        .into_iter()
-        .map(|q| handle_single_query_fwd(q, &query.header, udp_socket, resolver_addr))
-        .fold(
-            DnsMessage::new(header, Vec::new(), Vec::new()),
-            |mut acc, mut elem| {
-                acc.questions.append(&mut elem.questions);
-                acc.answers.append(&mut elem.answers);
-                acc
-            },
-        )
-        .serialize();
+    DnsMessage::new(header, query.questions, Vec::new())
+}
+
+fn format_error(header: Header) -> DnsMessage {
+    let header = Header {
+        qr_ind: QueryResponseIndicator::Response,
+        is_auth_ans: false,
+        is_trunc: false,
+        is_rec_available: false,
+        r_code: ResponseCode::FormatError,
+        qd_count: 0,
+        an_count: 0,
+        ns_count: 0,
+        ar_count: 0,
+        ..header
+    };
 
-    udp_socket.send_to(&response, source)
+    DnsMessage::new(header, Vec::new(), Vec::new())
 }
 
-fn handle_single_query_fwd(
-    query: Question,
-    header: &Header,
-    udp_socket: &UdpSocket,
+fn resolve_query(mut query: DnsMessage, config: &DnsServerConfig) -> DnsMessage {
+    let original_names = rewrite_questions(&mut query.questions, &config.rewrites);
+
+    let mut response = get_response(&query, config);
+    restore_names(&mut response, original_names);
+    apply_answer_policies(&mut response.answers, config);
+    response.fix_counts();
+
+    response
+}
+
+const MAX_QUESTIONS: usize = 8;
+
+fn handle_query_fwd(
+    mut query: DnsMessage,
     resolver_addr: &str,
+    config: &DnsServerConfig,
 ) -> DnsMessage {
-    let mut fwd_buf: [u8; 512] = [0; 512];
+    let original_names = rewrite_questions(&mut query.questions, &config.rewrites);
+    let upstream = &UdpUpstream::new(resolver_addr, config.upstream_timeout);
 
-    let header = Header {
-        qd_count: 1,
-        ..*header
+    let response = DnsMessage::response_for(&query)
+        .recursion_available(config.advertise_recursion)
+        .max_udp_payload(config.max_udp_payload)
+        .build();
+    let header = response.header;
+
+    let responses: Vec<DnsMessage> = thread::scope(|scope| {
+        let handles: Vec<_> = query
+            .questions
+            .into_iter()
+            .zip(original_names)
+            .map(|(q, original)| {
+                scope.spawn(move || {
+                    let client_question = Question::new(original, q.record_type, q.class);
+                    let rewritten = q.name.clone();
+
+                    let mut response = match local_response(&q, config) {
+                        Some((r_code, answers)) => {
+                            DnsMessage::new(Header { r_code, ..header }, vec![q], answers)
+                        }
+                        None => forward_with_cache(q, header, upstream, config),
+                    };
+
+                    restore_question(&mut response, &rewritten, client_question);
+                    response
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("Forwarding thread panicked"))
+            .collect()
+    });
+
+    let mut response = responses.into_iter().fold(response, |mut acc, mut elem| {
+        acc.header.r_code = merge_r_codes(acc.header.r_code, elem.header.r_code);
+        acc.header.is_trunc |= elem.header.is_trunc;
+        acc.questions.append(&mut elem.questions);
+        acc.answers.append(&mut elem.answers);
+        acc.authorities.append(&mut elem.authorities);
+        // The upstream's OPT record describes its EDNS state, not ours.
+        acc.additionals.extend(
+            elem.additionals
+                .into_iter()
+                .filter(|record| record.record_type != TYPE_OPT),
+        );
+        acc
+    });
+
+    apply_answer_policies(&mut response.answers, config);
+    response.fix_counts();
+
+    response
+}
+
+fn forward_with_cache(
+    question: Question,
+    header: Header,
+    upstream: &dyn Upstream,
+    config: &DnsServerConfig,
+) -> DnsMessage {
+    let cache = match &config.cache {
+        Some(cache) if config.is_cacheable(&question.name) => cache,
+        _ => return handle_single_query_fwd(question, upstream, config),
     };
 
-    let msg = DnsMessage::new(header, vec![query], Vec::new()).serialize();
+    if let Some(answers) = cache.get(&question) {
+        return DnsMessage::new(header, vec![question], answers);
+    }
+
+    let key = Question::new(question.name.clone(), question.record_type, question.class);
+    let response = handle_single_query_fwd(question, upstream, config);
+
+    // A truncated reply may be missing records, so it is not worth keeping.
+    if matches!(response.header.r_code, ResponseCode::NoError) && !response.header.is_trunc {
+        cache.insert(&key, &response.answers);
+    }
+
+    response
+}
+
+fn handle_single_query_fwd(
+    mut query: Question,
+    upstream: &dyn Upstream,
+    config: &DnsServerConfig,
+) -> DnsMessage {
+    if let Some(rng) = &config.case_rng {
+        query.name = randomize_case(&query.name, &mut rng.lock().unwrap());
+    }
+
+    let msg = DnsMessage::new_query(config.id_generator.as_ref(), vec![query]);
+
+    let response = upstream
+        .query(&msg)
+        .and_then(|response| match config.case_rng {
+            Some(_) => verify_question_case(&msg, response),
+            None => Ok(response),
+        });
+
+    match response {
+        Ok(response) => response,
+        Err(err) => {
+            config.logger.warn(format_args!(
+                "Upstream query {} failed: {}",
+                msg.header.packet_id, err
+            ));
+
+            let r_code = match err {
+                DnsError::Encode(_) => ResponseCode::FormatError,
+                _ => ResponseCode::ServerFailure,
+            };
+            let header = Header {
+                r_code,
+                ..msg.header
+            };
+            DnsMessage::new(header, msg.questions, Vec::new())
+        }
+    }
+}
+
+fn randomize_case(name: &str, rng: &mut Rng) -> String {
+    name.chars()
+        .map(|c| match rng.below(2) {
+            0 => c.to_ascii_lowercase(),
+            _ => c.to_ascii_uppercase(),
+        })
+        .collect()
+}
 
-    udp_socket
-        .send_to(&msg, resolver_addr)
-        .expect("Failed to forward query");
+fn verify_question_case(sent: &DnsMessage, response: DnsMessage) -> Result<DnsMessage, DnsError> {
+    let sent = sent.question().map(|q| q.name.as_str()).unwrap_or_default();
+    let received = response
+        .question()
+        .map(|q| q.name.as_str())
+        .unwrap_or_default();
+
+    if sent != received {
+        return Err(DnsError::QuestionMismatch {
+            sent: sent.to_owned(),
+            received: received.to_owned(),
+        });
+    }
 
-    udp_socket.recv_from(&mut fwd_buf).unwrap();
-    
-    DnsMessage::deserialize(&fwd_buf)
+    Ok(response)
 }
 
-fn get_response(query: &DnsMessage) -> DnsMessage {
-    let r_code = match query.header.op_code {
+fn get_response(query: &DnsMessage, config: &DnsServerConfig) -> DnsMessage {
+    let mut r_code = match query.header.op_code {
         OperationCode::Query => ResponseCode::NoError,
         _ => ResponseCode::NotImplemented,
     };
 
-    let (questions, answers): (Vec<Question>, Vec<Answer>) = query
-        .questions
+    let mut questions = Vec::new();
+    let mut answers = Vec::new();
+
+    for q in &query.questions {
+        let q = Question::new(q.name.clone(), q.record_type, q.class);
+
+        match local_response(&q, config) {
+            Some((local_r_code, mut local_answers)) => {
+                r_code = merge_r_codes(r_code, local_r_code);
+                answers.append(&mut local_answers);
+            }
+            None => match &config.zone {
+                Some(zone) => match zone.lookup(&q.name, q.record_type) {
+                    Some(mut zone_answers) => answers.append(&mut zone_answers),
+                    None => r_code = merge_r_codes(r_code, ResponseCode::NameError),
+                },
+                None => answers.push(Answer::new(q.name.clone(), 1, 1, 60, 4, vec![8, 8, 8, 8])),
+            },
+        }
+
+        questions.push(q);
+    }
+
+    DnsMessage::response_for(query)
+        .authoritative(config.zone.is_some())
+        .recursion_available(config.advertise_recursion)
+        .max_udp_payload(config.max_udp_payload)
+        .r_code(r_code)
+        .questions(questions)
+        .answers(answers)
+        .build()
+}
+
+fn merge_r_codes(current: ResponseCode, next: ResponseCode) -> ResponseCode {
+    match current {
+        ResponseCode::NoError => next,
+        _ => current,
+    }
+}
+
+const LOCAL_ANSWER_TTL: u32 = 60;
+
+fn local_response(
+    question: &Question,
+    config: &DnsServerConfig,
+) -> Option<(ResponseCode, Vec<Answer>)> {
+    if config.disabled_types.contains(&question.record_type) {
+        return Some((ResponseCode::NoError, Vec::new()));
+    }
+
+    if config.aaaa_nodata && question.record_type == 28 {
+        return Some((ResponseCode::NoError, Vec::new()));
+    }
+
+    if question.name.trim_end_matches('.').is_empty() && config.answers_root() {
+        return Some(root_response(question, config));
+    }
+
+    if let Some(answers) = dns_sd_services_answers(question, config) {
+        return Some((ResponseCode::NoError, answers));
+    }
+
+    let is_authors_bind =
+        question.class == CLASS_CHAOS && question.name.eq_ignore_ascii_case("authors.bind");
+
+    if is_authors_bind && !config.chaos_authors.is_empty() {
+        return Some((
+            ResponseCode::NoError,
+            chaos_authors_answers(question, config),
+        ));
+    }
+
+    if let Some(action) = config.special_use_action(&question.name) {
+        return match action {
+            SpecialUseAction::NameError => Some((ResponseCode::NameError, Vec::new())),
+            SpecialUseAction::Refuse => Some((ResponseCode::Refused, Vec::new())),
+            SpecialUseAction::Resolve => None,
+        };
+    }
+
+    if config.serve_loopback_reverse && is_loopback_reverse(&question.name) {
+        return Some(loopback_reverse_response(question));
+    }
+
+    if !config.serve_localhost || !is_localhost(&question.name) {
+        return None;
+    }
+
+    let name = question.name.clone();
+
+    let answers = match (question.record_type, question.class) {
+        (1, 1) => vec![Answer::new(
+            name,
+            1,
+            1,
+            LOCAL_ANSWER_TTL,
+            4,
+            vec![127, 0, 0, 1],
+        )],
+        (28, 1) => {
+            let data = Ipv6Addr::LOCALHOST.octets().to_vec();
+            vec![Answer::new(name, 28, 1, LOCAL_ANSWER_TTL, 16, data)]
+        }
+        _ => Vec::new(),
+    };
+
+    Some((ResponseCode::NoError, answers))
+}
+
+fn root_response(question: &Question, config: &DnsServerConfig) -> (ResponseCode, Vec<Answer>) {
+    let answer = |record_type: u16, data: Vec<u8>| {
+        let length = data.len().try_into().unwrap();
+        Answer::new(
+            question.name.clone(),
+            record_type,
+            question.class,
+            LOCAL_ANSWER_TTL,
+            length,
+            data,
+        )
+    };
+
+    match question.record_type {
+        2 => {
+            let answers = config
+                .root_ns
+                .iter()
+                .filter_map(|ns| encode_name(ns).ok())
+                .map(|data| answer(2, data))
+                .collect();
+            (ResponseCode::NoError, answers)
+        }
+        6 => {
+            let answers = config.root_soa.iter().map(|soa| answer(6, soa.clone()));
+            (ResponseCode::NoError, answers.collect())
+        }
+        _ => (ResponseCode::Refused, Vec::new()),
+    }
+}
+
+fn parse_soa(value: &str) -> Option<Vec<u8>> {
+    let fields: Vec<&str> = value.split_whitespace().collect();
+
+    let [mname, rname, timers @ ..] = fields.as_slice() else {
+        return None;
+    };
+
+    if timers.len() != 5 {
+        return None;
+    }
+
+    let mut rdata = encode_name(mname).ok()?;
+    rdata.extend(encode_name(rname).ok()?);
+
+    for timer in timers {
+        rdata.extend(timer.parse::<u32>().ok()?.to_be_bytes());
+    }
+
+    Some(rdata)
+}
+
+const DNS_SD_SERVICES_PREFIX: &str = "_services._dns-sd._udp.";
+
+fn dns_sd_services_answers(question: &Question, config: &DnsServerConfig) -> Option<Vec<Answer>> {
+    let name = question.name.trim_end_matches('.').to_ascii_lowercase();
+    let domain = name.strip_prefix(DNS_SD_SERVICES_PREFIX)?;
+
+    let services: Vec<&String> = config
+        .dns_sd_services
         .iter()
-        .map(|q| {
-            let q = Question::new(q.name.clone(), q.record_type, q.class);
-            let a = Answer::new(q.name.clone(), 1, 1, 60, 4, vec![8, 8, 8, 8]);
-            (q, a)
+        .filter(|(d, _)| d == domain)
+        .map(|(_, service)| service)
+        .collect();
+
+    if services.is_empty() {
+        return None;
+    }
+
+    if question.record_type != 12 {
+        return Some(Vec::new());
+    }
+
+    let answers = services
+        .into_iter()
+        .filter_map(|service| encode_name(&format!("{service}.{domain}")).ok())
+        .map(|data| {
+            Answer::new(
+                question.name.clone(),
+                12,
+                question.class,
+                LOCAL_ANSWER_TTL,
+                data.len().try_into().unwrap(),
+                data,
+            )
         })
         .collect();
 
-    let header = Header {
-        packet_id: query.header.packet_id,
-        qr_ind: QueryResponseIndicator::Response,
-        op_code: query.header.op_code,
-        is_auth_ans: false,
-        is_trunc: false,
-        is_rec_desired: query.header.is_rec_desired,
-        is_rec_available: false,
-        r_code,
-        qd_count: query.header.qd_count,
-        an_count: answers.len().try_into().unwrap(),
-        ns_count: 0,
-        ar_count: 0,
-    };
+    Some(answers)
+}
+
+const CLASS_CHAOS: u16 = 3;
+
+fn chaos_authors_answers(question: &Question, config: &DnsServerConfig) -> Vec<Answer> {
+    if question.record_type != 16 {
+        return Vec::new();
+    }
+
+    config
+        .chaos_authors
+        .iter()
+        .map(|author| {
+            let data = encode_character_strings(&[author]);
 
-    DnsMessage::new(header, questions, answers)
+            Answer::new(
+                question.name.clone(),
+                16,
+                CLASS_CHAOS,
+                0,
+                data.len().try_into().unwrap(),
+                data,
+            )
+        })
+        .collect()
 }
 
-pub struct DnsServerConfig {
-    bind_addr: String,
-    mode: DnsServerMode,
+fn is_localhost(name: &str) -> bool {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    name == "localhost" || name.ends_with(".localhost")
 }
 
-enum DnsServerMode {
-    ResolvingServer,
-    ForwardingServer(String),
+const LOOPBACK_REVERSE_ZONE: &str = "127.in-addr.arpa";
+
+fn is_loopback_reverse(name: &str) -> bool {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+    name == LOOPBACK_REVERSE_ZONE
+        || name.ends_with(&format!(".{LOOPBACK_REVERSE_ZONE}"))
+        || name == ipv6_reverse_name(&Ipv6Addr::LOCALHOST)
 }
 
-impl DnsServerConfig {
-    pub fn new(mut args: impl Iterator<Item = String>) -> Self {
-        args.next();
+/// Maps every full loopback address in the reverse zone to `localhost`.
+/// Partial addresses such as `0.127.in-addr.arpa` exist but own no records;
+/// any other name in the zone does not exist.
+fn loopback_reverse_response(question: &Question) -> (ResponseCode, Vec<Answer>) {
+    let name = question.name.trim_end_matches('.').to_ascii_lowercase();
 
-        let bind_addr = "127.0.0.1:2053".to_owned();
+    let is_address = match name.strip_suffix(LOOPBACK_REVERSE_ZONE) {
+        Some(prefix) => {
+            let labels: Vec<&str> = match prefix.trim_end_matches('.') {
+                "" => Vec::new(),
+                prefix => prefix.split('.').collect(),
+            };
 
-        let mode = match args.next() {
-            Some(arg) if arg == "--resolver" => {
-                if let Some(argv) = args.next() {
-                    DnsServerMode::ForwardingServer(argv)
-                } else {
-                    DnsServerMode::ResolvingServer
-                }
+            if labels.len() > 3 || !labels.iter().all(|label| is_octet_label(label)) {
+                return (ResponseCode::NameError, Vec::new());
             }
-            _ => DnsServerMode::ResolvingServer,
-        };
+            labels.len() == 3
+        }
+        // The only IPv6 name in the zone is the one for ::1.
+        None => true,
+    };
+
+    if !is_address || (question.record_type, question.class) != (12, 1) {
+        return (ResponseCode::NoError, Vec::new());
+    }
+
+    let data = encode_name("localhost").unwrap();
+    let answer = Answer::new(
+        question.name.clone(),
+        12,
+        1,
+        LOCAL_ANSWER_TTL,
+        data.len().try_into().unwrap(),
+        data,
+    );
+
+    (ResponseCode::NoError, vec![answer])
+}
+
+/// Whether `label` is an octet written in plain decimal, as in a reverse
+/// name.
+fn is_octet_label(label: &str) -> bool {
+    label
+        .parse::<u8>()
+        .is_ok_and(|octet| octet.to_string() == label)
+}
+
+const MAX_REWRITE_DEPTH: usize = 8;
+
+fn rewrite_name(name: &str, rewrites: &HashMap<String, String>) -> String {
+    let mut name = name.to_owned();
+
+    for _ in 0..MAX_REWRITE_DEPTH {
+        match rewrites.get(&name.to_ascii_lowercase()) {
+            Some(target) => name = target.clone(),
+            None => break,
+        }
+    }
+
+    name
+}
+
+fn rewrite_questions(
+    questions: &mut [Question],
+    rewrites: &HashMap<String, String>,
+) -> Vec<String> {
+    questions
+        .iter_mut()
+        .map(|q| {
+            let rewritten = rewrite_name(&q.name, rewrites);
+            std::mem::replace(&mut q.name, rewritten)
+        })
+        .collect()
+}
+
+fn restore_names(response: &mut DnsMessage, original_names: Vec<String>) {
+    for (question, original) in response.questions.iter_mut().zip(original_names) {
+        response
+            .answers
+            .iter_mut()
+            .filter(|a| a.name.eq_ignore_ascii_case(&question.name))
+            .for_each(|a| a.name = original.clone());
+
+        question.name = original;
+    }
+}
+
+/// Answers the client's own question rather than whatever the upstream
+/// echoed, which may be missing, repeated or still carry the rewritten name.
+fn restore_question(response: &mut DnsMessage, rewritten: &str, question: Question) {
+    response
+        .answers
+        .iter_mut()
+        .filter(|a| a.name.eq_ignore_ascii_case(rewritten))
+        .for_each(|a| a.name = question.name.clone());
+
+    response.questions = vec![question];
+}
+
+fn apply_answer_policies(answers: &mut Vec<Answer>, config: &DnsServerConfig) {
+    answers.retain(|a| !config.disabled_types.contains(&a.record_type));
+
+    group_rrsets(answers);
+    normalize_rrset_ttls(answers);
+
+    if let Some(jitter) = &config.ttl_jitter {
+        apply_ttl_jitter(answers, jitter);
+    }
+
+    if let Some(min_ttl) = config.min_ttl {
+        apply_min_ttl(answers, min_ttl);
+    }
+
+    if let Some(rng) = &config.shuffle_rng {
+        shuffle_answers(answers, &mut rng.lock().unwrap());
+    }
+}
+
+fn group_rrsets(answers: &mut [Answer]) {
+    let mut first_seen: HashMap<(String, u16, u16), usize> = HashMap::new();
+
+    for (idx, answer) in answers.iter().enumerate() {
+        let key = (
+            answer.name.to_ascii_lowercase(),
+            answer.record_type,
+            answer.class,
+        );
+        first_seen.entry(key).or_insert(idx);
+    }
+
+    answers.sort_by_key(|answer| {
+        first_seen[&(
+            answer.name.to_ascii_lowercase(),
+            answer.record_type,
+            answer.class,
+        )]
+    });
+}
+
+fn normalize_rrset_ttls(answers: &mut [Answer]) {
+    let mut min_ttls: HashMap<(String, u16, u16), u32> = HashMap::new();
+
+    for answer in answers.iter() {
+        let key = (
+            answer.name.to_ascii_lowercase(),
+            answer.record_type,
+            answer.class,
+        );
+        let ttl = min_ttls.entry(key).or_insert(answer.time_to_live);
+        *ttl = (*ttl).min(answer.time_to_live);
+    }
+
+    for answer in answers.iter_mut() {
+        let key = (
+            answer.name.to_ascii_lowercase(),
+            answer.record_type,
+            answer.class,
+        );
+        answer.time_to_live = min_ttls[&key];
+    }
+}
+
+fn apply_ttl_jitter(answers: &mut [Answer], jitter: &TtlJitter) {
+    let offset = jitter.rng.lock().unwrap().below(jitter.max as usize + 1) as u32;
+
+    answers
+        .iter_mut()
+        .for_each(|a| a.time_to_live = a.time_to_live.saturating_sub(offset));
+}
+
+fn apply_min_ttl(answers: &mut [Answer], min_ttl: u32) {
+    answers
+        .iter_mut()
+        .for_each(|a| a.time_to_live = a.time_to_live.max(min_ttl));
+}
+
+fn shuffle_answers(answers: &mut [Answer], rng: &mut Rng) {
+    let mut rrsets: Vec<((String, u16), Vec<usize>)> = Vec::new();
+
+    for (idx, answer) in answers.iter().enumerate() {
+        if answer.record_type != 1 && answer.record_type != 28 {
+            continue;
+        }
+
+        let key = (answer.name.to_ascii_lowercase(), answer.record_type);
+
+        match rrsets.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, positions)) => positions.push(idx),
+            None => rrsets.push((key, vec![idx])),
+        }
+    }
+
+    for (_, positions) in rrsets {
+        for i in (1..positions.len()).rev() {
+            answers.swap(positions[i], positions[rng.below(i + 1)]);
+        }
+    }
+}
+
+pub struct DnsServerConfig {
+    bind_addr: SocketAddr,
+    mode: DnsServerMode,
+    advertise_recursion: bool,
+    min_ttl: Option<u32>,
+    rewrites: HashMap<String, String>,
+    shuffle_rng: Option<Mutex<Rng>>,
+    ttl_jitter: Option<TtlJitter>,
+    case_rng: Option<Mutex<Rng>>,
+    allowlist: Vec<Cidr>,
+    denied_action: DeniedQueryAction,
+    serve_localhost: bool,
+    serve_loopback_reverse: bool,
+    special_use_domains: Vec<(String, SpecialUseAction)>,
+    disabled_types: HashSet<u16>,
+    aaaa_nodata: bool,
+    tcp: bool,
+    parse_options: ParseOptions,
+    chaos_authors: Vec<String>,
+    dns_sd_services: Vec<(String, String)>,
+    root_ns: Vec<String>,
+    root_soa: Option<Vec<u8>>,
+    /// Reply size limit for UDP clients that send no OPT record. Clients
+    /// that do are held to their advertised size instead, as clamped by
+    /// `max_udp_payload`.
+    no_edns_udp_size: usize,
+    workers: usize,
+    /// Largest UDP payload size advertised in our OPT record, and so the
+    /// most an EDNS client can negotiate.
+    max_udp_payload: u16,
+    oversized_action: OversizedResponseAction,
+    upstream_timeout: Duration,
+    cache: Option<Cache>,
+    no_cache_names: HashSet<String>,
+    zone: Option<Zone>,
+    logger: Logger,
+    id_generator: Box<dyn IdGenerator>,
+}
+
+struct TtlJitter {
+    max: u32,
+    rng: Mutex<Rng>,
+}
+
+enum DnsServerMode {
+    ResolvingServer,
+    ForwardingServer(String),
+}
+
+enum DeniedQueryAction {
+    Refuse,
+    Drop,
+}
+
+enum OversizedResponseAction {
+    HeaderOnly,
+    Drop,
+}
+
+pub(crate) fn parse_record_type(value: &str) -> Option<u16> {
+    match value.to_ascii_uppercase().as_str() {
+        "A" => Some(1),
+        "NS" => Some(2),
+        "CNAME" => Some(5),
+        "SOA" => Some(6),
+        "PTR" => Some(12),
+        "MX" => Some(15),
+        "TXT" => Some(16),
+        "AAAA" => Some(28),
+        "SRV" => Some(33),
+        v => v.parse().ok(),
+    }
+}
+
+fn load_zone(path: String) -> Result<Zone, ConfigError> {
+    let zone = match fs::read_to_string(&path) {
+        Ok(contents) => contents.parse::<Zone>().map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+
+    zone.map_err(|reason| ConfigError::InvalidZone { path, reason })
+}
+
+#[derive(Copy, Clone)]
+enum SpecialUseAction {
+    NameError,
+    Refuse,
+    Resolve,
+}
+
+impl SpecialUseAction {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "nxdomain" => Some(Self::NameError),
+            "refuse" => Some(Self::Refuse),
+            "resolve" => Some(Self::Resolve),
+            _ => None,
+        }
+    }
+}
+
+impl DnsServerConfig {
+    pub fn new(mut args: impl Iterator<Item = String>) -> Result<Self, ConfigError> {
+        args.next();
+
+        let mut bind_addr = SocketAddr::from(([127, 0, 0, 1], 2053));
+
+        let mut mode = DnsServerMode::ResolvingServer;
+        let mut advertise_recursion = None;
+        let mut min_ttl = None;
+        let mut rewrites = HashMap::new();
+        let mut shuffle_rng = None;
+        let mut ttl_jitter_max = None;
+        let mut ttl_jitter_seed = None;
+        let mut case_rng = None;
+        let mut allowlist = Vec::new();
+        let mut denied_action = DeniedQueryAction::Refuse;
+        let mut serve_localhost = true;
+        let mut serve_loopback_reverse = true;
+        let mut disabled_types = HashSet::new();
+        let mut aaaa_nodata = false;
+        let mut tcp = false;
+        let mut parse_options = ParseOptions::default();
+        let mut chaos_authors = Vec::new();
+        let mut dns_sd_services = Vec::new();
+        let mut root_ns = Vec::new();
+        let mut root_soa = None;
+        let mut no_edns_udp_size = UDP_MAX_SIZE;
+        let mut workers = DEFAULT_WORKERS;
+        let mut max_udp_payload = 4096;
+        let mut oversized_action = OversizedResponseAction::HeaderOnly;
+        let mut upstream_timeout = Duration::from_secs(5);
+        let mut log_level = LogLevel::Info;
+        let mut cache = None;
+        let mut no_cache_names = HashSet::new();
+        let mut zone = None;
+        let mut special_use_domains = vec![
+            ("invalid".to_owned(), SpecialUseAction::NameError),
+            ("test".to_owned(), SpecialUseAction::NameError),
+            ("example".to_owned(), SpecialUseAction::Resolve),
+            ("local".to_owned(), SpecialUseAction::NameError),
+        ];
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--bind" => {
+                    let argv = args.next().ok_or(ConfigError::MissingValue("--bind"))?;
+                    bind_addr = argv
+                        .parse()
+                        .map_err(|_| ConfigError::InvalidBindAddress(argv))?;
+                }
+                "--resolver" => {
+                    if let Some(argv) = args.next() {
+                        mode = DnsServerMode::ForwardingServer(argv);
+                    }
+                }
+                "--advertise-recursion" => advertise_recursion = Some(true),
+                "--no-advertise-recursion" => advertise_recursion = Some(false),
+                "--min-ttl" => {
+                    let argv = args.next().ok_or(ConfigError::MissingValue("--min-ttl"))?;
+                    let ttl = argv.parse().map_err(|_| ConfigError::InvalidValue {
+                        flag: "--min-ttl",
+                        value: argv,
+                    })?;
+                    min_ttl = Some(ttl);
+                }
+                "--rewrite" => {
+                    if let Some((from, to)) = args.next().as_ref().and_then(|a| a.split_once('=')) {
+                        rewrites.insert(from.to_ascii_lowercase(), to.to_owned());
+                    }
+                }
+                "--shuffle-answers" => {
+                    shuffle_rng = shuffle_rng.or_else(|| Some(Rng::from_entropy()));
+                }
+                "--shuffle-seed" => {
+                    shuffle_rng = args.next().and_then(|argv| argv.parse().ok()).map(Rng::new);
+                }
+                "--ttl-jitter" => {
+                    ttl_jitter_max = args.next().and_then(|argv| argv.parse().ok());
+                }
+                "--ttl-jitter-seed" => {
+                    ttl_jitter_seed = args.next().and_then(|argv| argv.parse().ok());
+                }
+                "--use-0x20" => case_rng = Some(Rng::from_entropy()),
+                "--allow" => {
+                    let argv = args.next().ok_or(ConfigError::MissingValue("--allow"))?;
+                    let cidr = argv.parse().map_err(|_| ConfigError::InvalidCidr(argv))?;
+                    allowlist.push(cidr);
+                }
+                "--deny-action" => {
+                    let argv = args
+                        .next()
+                        .ok_or(ConfigError::MissingValue("--deny-action"))?;
+                    denied_action = match argv.as_str() {
+                        "drop" => DeniedQueryAction::Drop,
+                        "refuse" => DeniedQueryAction::Refuse,
+                        _ => {
+                            return Err(ConfigError::InvalidValue {
+                                flag: "--deny-action",
+                                value: argv,
+                            })
+                        }
+                    };
+                }
+                "--no-localhost" => serve_localhost = false,
+                "--no-loopback-reverse" => serve_loopback_reverse = false,
+                "--aaaa-nodata" => aaaa_nodata = true,
+                "--tcp" => tcp = true,
+                "--allow-forward-pointers" => parse_options.allow_forward_pointers = true,
+                "--root-ns" => {
+                    if let Some(ns) = args.next() {
+                        root_ns.push(ns);
+                    }
+                }
+                "--root-soa" => {
+                    if let Some(soa) = args.next().and_then(|argv| parse_soa(&argv)) {
+                        root_soa = Some(soa);
+                    }
+                }
+                "--dns-sd-service" => {
+                    let rule = args.next();
+                    let rule = rule.as_ref().and_then(|argv| argv.split_once('='));
+
+                    if let Some((domain, service)) = rule {
+                        let domain = domain.trim_matches('.').to_ascii_lowercase();
+                        dns_sd_services.push((domain, service.trim_matches('.').to_owned()));
+                    }
+                }
+                "--chaos-author" => {
+                    if let Some(author) = args.next() {
+                        chaos_authors.push(author);
+                    }
+                }
+                "--no-edns-udp-size" => {
+                    if let Some(size) = args.next().and_then(|argv| argv.parse().ok()) {
+                        no_edns_udp_size = size;
+                    }
+                }
+                "--workers" => {
+                    if let Some(count) = args.next().and_then(|argv| argv.parse().ok()) {
+                        workers = usize::max(count, 1);
+                    }
+                }
+                "--cache" => cache = Some(Cache::new()),
+                "--no-cache" => {
+                    if let Some(name) = args.next() {
+                        no_cache_names.insert(name.trim_matches('.').to_ascii_lowercase());
+                    }
+                }
+                "--zone" => {
+                    let path = args.next().ok_or(ConfigError::MissingValue("--zone"))?;
+                    zone = Some(load_zone(path)?);
+                }
+                "--log-level" => {
+                    if let Some(level) = args.next().and_then(|argv| LogLevel::parse(&argv)) {
+                        log_level = level;
+                    }
+                }
+                "--upstream-timeout-ms" => {
+                    if let Some(ms) = args.next().and_then(|argv| argv.parse().ok()) {
+                        upstream_timeout = Duration::from_millis(ms);
+                    }
+                }
+                "--max-udp-size" => {
+                    if let Some(size) = args.next().and_then(|argv| argv.parse().ok()) {
+                        max_udp_payload = size;
+                    }
+                }
+                "--oversized-action" => match args.next().as_deref() {
+                    Some("header") => oversized_action = OversizedResponseAction::HeaderOnly,
+                    Some("drop") => oversized_action = OversizedResponseAction::Drop,
+                    _ => {}
+                },
+                "--disable-type" => {
+                    if let Some(record_type) = args.next().and_then(|a| parse_record_type(&a)) {
+                        disabled_types.insert(record_type);
+                    }
+                }
+                "--special-use" => {
+                    let argv = args
+                        .next()
+                        .ok_or(ConfigError::MissingValue("--special-use"))?;
+                    let rule = argv.split_once('=').and_then(|(domain, action)| {
+                        Some((domain, SpecialUseAction::parse(action)?))
+                    });
+                    let Some((domain, action)) = rule else {
+                        return Err(ConfigError::InvalidValue {
+                            flag: "--special-use",
+                            value: argv,
+                        });
+                    };
+
+                    let domain = domain.trim_matches('.').to_ascii_lowercase();
+                    special_use_domains.retain(|(d, _)| *d != domain);
+                    special_use_domains.push((domain, action));
+                }
+                _ => {}
+            }
+        }
+
+        let ttl_jitter = ttl_jitter_max.map(|max| TtlJitter {
+            max,
+            rng: Mutex::new(ttl_jitter_seed.map_or_else(Rng::from_entropy, Rng::new)),
+        });
+
+        let advertise_recursion =
+            advertise_recursion.unwrap_or(matches!(mode, DnsServerMode::ForwardingServer(_)));
+
+        Ok(DnsServerConfig {
+            bind_addr,
+            mode,
+            advertise_recursion,
+            min_ttl,
+            rewrites,
+            shuffle_rng: shuffle_rng.map(Mutex::new),
+            ttl_jitter,
+            case_rng: case_rng.map(Mutex::new),
+            allowlist,
+            denied_action,
+            serve_localhost,
+            serve_loopback_reverse,
+            special_use_domains,
+            disabled_types,
+            aaaa_nodata,
+            tcp,
+            parse_options,
+            chaos_authors,
+            dns_sd_services,
+            root_ns,
+            root_soa,
+            no_edns_udp_size,
+            workers,
+            max_udp_payload,
+            oversized_action,
+            upstream_timeout,
+            cache,
+            no_cache_names,
+            zone,
+            logger: Logger::new(log_level, StdioSink),
+            id_generator: Box::new(RandomIdGenerator),
+        })
+    }
+
+    fn special_use_action(&self, name: &str) -> Option<SpecialUseAction> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+        self.special_use_domains
+            .iter()
+            .find(|(domain, _)| name == *domain || name.ends_with(&format!(".{domain}")))
+            .map(|(_, action)| *action)
+    }
+
+    fn answers_root(&self) -> bool {
+        !self.root_ns.is_empty() || self.root_soa.is_some()
+    }
+
+    fn is_cacheable(&self, name: &str) -> bool {
+        !self
+            .no_cache_names
+            .contains(&name.trim_matches('.').to_ascii_lowercase())
+    }
+
+    fn is_source_allowed(&self, addr: IpAddr) -> bool {
+        self.allowlist.is_empty() || self.allowlist.iter().any(|cidr| cidr.contains(addr))
+    }
+
+    pub fn with_id_generator(self, id_generator: impl IdGenerator + 'static) -> Self {
+        DnsServerConfig {
+            id_generator: Box::new(id_generator),
+            ..self
+        }
+    }
+
+    pub fn with_log_sink(self, sink: impl LogSink + 'static) -> Self {
+        let level = self.logger.level();
+
+        DnsServerConfig {
+            logger: Logger::new(level, sink),
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::id_generator::SequentialIdGenerator;
+
+    use std::net::Shutdown;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Instant;
+
+    fn config_from(args: &[&str]) -> DnsServerConfig {
+        let args = ["program"].iter().chain(args).map(|arg| arg.to_string());
+        DnsServerConfig::new(args).unwrap()
+    }
+
+    fn query_for(name: &str, record_type: u16) -> DnsMessage {
+        let header = Header {
+            packet_id: 1234,
+            qr_ind: QueryResponseIndicator::Query,
+            op_code: OperationCode::Query,
+            is_auth_ans: false,
+            is_trunc: false,
+            is_rec_desired: true,
+            is_rec_available: false,
+            r_code: ResponseCode::NoError,
+            qd_count: 1,
+            an_count: 0,
+            ns_count: 0,
+            ar_count: 0,
+        };
+
+        let question = Question::new(name.to_owned(), record_type, 1);
+
+        DnsMessage::new(header, vec![question], Vec::new())
+    }
+
+    fn spawn_upstream(reply: impl Fn(DnsMessage) -> DnsMessage + Send + 'static) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+
+            while let Ok((size, source)) = socket.recv_from(&mut buf) {
+                let response = reply(DnsMessage::deserialize(&buf[..size]).unwrap());
+                socket
+                    .send_to(&response.to_bytes().unwrap(), source)
+                    .unwrap();
+            }
+        });
+
+        addr
+    }
+
+    /// Upstream answering every query with `ttl`, sending one message on the
+    /// returned receiver per query it received.
+    fn spawn_counting_upstream(ttl: u32) -> (SocketAddr, mpsc::Receiver<()>) {
+        let (sender, receiver) = mpsc::channel();
+        let addr = spawn_upstream(move |query| {
+            sender.send(()).unwrap();
+            answered(query, ttl)
+        });
+
+        (addr, receiver)
+    }
+
+    fn forward(query: &DnsMessage, config: &DnsServerConfig) -> DnsMessage {
+        handle(query, config).expect("No response received")
+    }
+
+    fn answered(query: DnsMessage, ttl: u32) -> DnsMessage {
+        let header = Header {
+            qr_ind: QueryResponseIndicator::Response,
+            an_count: 1,
+            ..query.header
+        };
+        let name = query.question().unwrap().name.clone();
+        let answer = Answer::new(name, 1, 1, ttl, 4, vec![1, 2, 3, 4]);
+
+        DnsMessage::new(header, query.questions, vec![answer])
+    }
+
+    fn upstream_answering(ttl: u32) -> SocketAddr {
+        spawn_upstream(move |query| answered(query, ttl))
+    }
+
+    #[test]
+    fn bind_flag_sets_address_regardless_of_order() {
+        let config = config_from(&["--bind", "0.0.0.0:53", "--resolver", "1.1.1.1:53"]);
+        assert_eq!(config.bind_addr, "0.0.0.0:53".parse().unwrap());
+
+        let config = config_from(&["--resolver", "1.1.1.1:53", "--bind", "[::1]:5353"]);
+        assert_eq!(config.bind_addr, "[::1]:5353".parse().unwrap());
+        assert!(matches!(config.mode, DnsServerMode::ForwardingServer(_)));
+    }
+
+    #[test]
+    fn resolving_server_answers_from_zone_file() {
+        let path = std::env::temp_dir().join(format!("zone-{}.txt", std::process::id()));
+        fs::write(
+            &path,
+            "www.example.com A 300 192.0.2.10\nexample.com MX 3600 10 mail.example.com\n",
+        )
+        .unwrap();
+        let config = config_from(&["--zone", path.to_str().unwrap()]);
+        fs::remove_file(&path).unwrap();
+
+        let a = handle(&query_for("www.example.com", 1), &config).unwrap();
+        let mx = handle(&query_for("example.com", 15), &config).unwrap();
+        let missing = handle(&query_for("missing.example.com", 1), &config).unwrap();
+
+        assert!(a.header.is_auth_ans);
+        assert_eq!(a.answers[0].time_to_live, 300);
+        assert_eq!(a.answers[0].data, [192, 0, 2, 10]);
+        assert_eq!(
+            mx.answers[0].as_mx(),
+            Some((10, "mail.example.com".to_owned()))
+        );
+        assert!(matches!(missing.header.r_code, ResponseCode::NameError));
+        assert!(missing.answers.is_empty());
+    }
+
+    #[test]
+    fn unreadable_zone_file_is_rejected() {
+        let args = ["program", "--zone", "/nonexistent/zone.txt"].map(String::from);
+
+        assert!(matches!(
+            DnsServerConfig::new(args.into_iter()),
+            Err(ConfigError::InvalidZone { .. })
+        ));
+    }
+
+    #[test]
+    fn invalid_bind_address_is_rejected() {
+        let args = ["program", "--bind", "localhost"].map(String::from);
+
+        assert_eq!(
+            DnsServerConfig::new(args.into_iter()).err(),
+            Some(ConfigError::InvalidBindAddress("localhost".to_owned()))
+        );
+    }
+
+    #[test]
+    fn invalid_allow_cidr_is_rejected() {
+        let args = ["program", "--allow", "10.0.0.0/33"].map(String::from);
+
+        assert_eq!(
+            DnsServerConfig::new(args.into_iter()).err(),
+            Some(ConfigError::InvalidCidr("10.0.0.0/33".to_owned()))
+        );
+    }
+
+    #[test]
+    fn invalid_deny_action_is_rejected() {
+        let args = ["program", "--deny-action", "Drop"].map(String::from);
+
+        assert_eq!(
+            DnsServerConfig::new(args.into_iter()).err(),
+            Some(ConfigError::InvalidValue {
+                flag: "--deny-action",
+                value: "Drop".to_owned(),
+            })
+        );
+
+        let args = ["program", "--deny-action"].map(String::from);
+
+        assert_eq!(
+            DnsServerConfig::new(args.into_iter()).err(),
+            Some(ConfigError::MissingValue("--deny-action"))
+        );
+    }
+
+    #[test]
+    fn invalid_min_ttl_is_rejected() {
+        let args = ["program", "--min-ttl", "300", "--min-ttl", "5m"].map(String::from);
+
+        assert_eq!(
+            DnsServerConfig::new(args.into_iter()).err(),
+            Some(ConfigError::InvalidValue {
+                flag: "--min-ttl",
+                value: "5m".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn malformed_special_use_rule_is_rejected() {
+        for rule in ["test", "test=ignore"] {
+            let args = ["program", "--special-use", rule].map(String::from);
+
+            assert_eq!(
+                DnsServerConfig::new(args.into_iter()).err(),
+                Some(ConfigError::InvalidValue {
+                    flag: "--special-use",
+                    value: rule.to_owned(),
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn example_domain_is_resolved_unless_overridden() {
+        let config = config_from(&[]);
+        let refusing = config_from(&["--special-use", "example=refuse"]);
+
+        assert!(matches!(
+            config.special_use_action("www.example"),
+            Some(SpecialUseAction::Resolve)
+        ));
+        assert!(matches!(
+            refusing.special_use_action("www.example"),
+            Some(SpecialUseAction::Refuse)
+        ));
+    }
+
+    #[test]
+    fn chaos_authors_bind_returns_configured_strings() {
+        let config = config_from(&["--chaos-author", "Alice", "--chaos-author", "Bob"]);
+        let mut query = query_for("authors.bind", 16);
+        query.questions[0].class = CLASS_CHAOS;
+
+        let response = handle(&query, &config).unwrap();
+
+        let data: Vec<&[u8]> = response.answers.iter().map(|a| a.data.as_slice()).collect();
+        assert_eq!(data, [b"\x05Alice".as_slice(), b"\x03Bob".as_slice()]);
+        assert!(response.answers.iter().all(|a| a.class == CLASS_CHAOS));
+    }
+
+    struct CapturingSink(Arc<Mutex<Vec<(LogLevel, String)>>>);
+
+    impl LogSink for CapturingSink {
+        fn write(&self, level: LogLevel, line: &str) {
+            self.0.lock().unwrap().push((level, line.to_owned()));
+        }
+    }
+
+    #[test]
+    fn handled_query_emits_info_line() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let config =
+            config_from(&["--log-level", "info"]).with_log_sink(CapturingSink(lines.clone()));
+
+        handle(&query_for("example.com", 1), &config).unwrap();
+
+        let lines = lines.lock().unwrap();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].0, LogLevel::Info);
+        assert!(lines[0].1.contains("example.com type=1 rcode=NOERROR"));
+    }
+
+    #[test]
+    fn root_soa_query_returns_configured_soa() {
+        let soa = "a.root.test. admin.test. 2024010101 1800 900 604800 86400";
+        let config = config_from(&["--root-soa", soa]);
+
+        let response = handle(&query_for("", 6), &config).unwrap();
+
+        let mut expected = encode_name("a.root.test").unwrap();
+        expected.extend(encode_name("admin.test").unwrap());
+        for timer in [2024010101u32, 1800, 900, 604800, 86400] {
+            expected.extend(timer.to_be_bytes());
+        }
+        assert!(matches!(response.header.r_code, ResponseCode::NoError));
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].data, expected);
+    }
+
+    #[test]
+    fn root_query_for_other_type_is_refused() {
+        let config = config_from(&["--root-ns", "a.root.test"]);
+
+        let response = handle(&query_for("", 1), &config).unwrap();
+
+        assert!(matches!(response.header.r_code, ResponseCode::Refused));
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn dns_sd_enumeration_lists_configured_service_types() {
+        let config = config_from(&[
+            "--dns-sd-service",
+            "example.com=_http._tcp",
+            "--dns-sd-service",
+            "example.com=_ipp._tcp",
+            "--dns-sd-service",
+            "example.org=_ssh._tcp",
+        ]);
+
+        let response = handle(
+            &query_for("_services._dns-sd._udp.example.com", 12),
+            &config,
+        )
+        .unwrap();
+
+        let data: Vec<&[u8]> = response.answers.iter().map(|a| a.data.as_slice()).collect();
+        assert_eq!(
+            data,
+            [
+                encode_name("_http._tcp.example.com").unwrap().as_slice(),
+                encode_name("_ipp._tcp.example.com").unwrap().as_slice()
+            ]
+        );
+    }
+
+    #[test]
+    fn seeded_ttl_jitter_stays_within_bound_below_nominal_ttl() {
+        let args = ["--ttl-jitter", "30", "--ttl-jitter-seed", "7"];
+
+        let ttls: Vec<u32> = (0..2)
+            .map(|_| {
+                let response = handle(&query_for("example.com", 1), &config_from(&args)).unwrap();
+                response.answers[0].time_to_live
+            })
+            .collect();
+
+        assert!((LOCAL_ANSWER_TTL - 30..=LOCAL_ANSWER_TTL).contains(&ttls[0]));
+        assert_eq!(ttls[0], ttls[1]);
+    }
+
+    #[test]
+    fn recursion_available_follows_config_independent_of_mode() {
+        let resolving = config_from(&["--advertise-recursion"]);
+        let response = handle(&query_for("example.com", 1), &resolving).unwrap();
+        assert!(response.header.is_rec_available);
+
+        let upstream = upstream_answering(60).to_string();
+        let forwarding = config_from(&["--resolver", &upstream, "--no-advertise-recursion"]);
+        let response = forward(&query_for("example.com", 1), &forwarding);
+        assert!(!response.header.is_rec_available);
+    }
+
+    #[test]
+    fn recursion_available_defaults_to_forwarding_mode() {
+        let response = handle(&query_for("example.com", 1), &config_from(&[])).unwrap();
+        assert!(!response.header.is_rec_available);
+
+        let upstream = upstream_answering(60).to_string();
+        let response = forward(
+            &query_for("example.com", 1),
+            &config_from(&["--resolver", &upstream]),
+        );
+        assert!(response.header.is_rec_available);
+    }
+
+    #[test]
+    fn forwarded_answer_ttl_is_raised_to_min_ttl() {
+        let upstream = upstream_answering(30).to_string();
+        let config = config_from(&["--resolver", &upstream, "--min-ttl", "300"]);
+
+        let response = forward(&query_for("example.com", 1), &config);
+
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].time_to_live, 300);
+    }
+
+    #[test]
+    fn forwarded_answer_ttl_above_min_ttl_is_kept() {
+        let upstream = upstream_answering(3600).to_string();
+        let config = config_from(&["--resolver", &upstream, "--min-ttl", "300"]);
+
+        let response = forward(&query_for("example.com", 1), &config);
+
+        assert_eq!(response.answers[0].time_to_live, 3600);
+    }
+
+    #[test]
+    fn resolved_answer_ttl_is_raised_to_min_ttl() {
+        let config = config_from(&["--min-ttl", "300"]);
+
+        let response = handle(&query_for("example.com", 1), &config).unwrap();
+
+        assert_eq!(response.answers[0].time_to_live, 300);
+    }
+
+    #[test]
+    fn unresponsive_upstream_yields_servfail_after_timeout() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstream = silent.local_addr().unwrap().to_string();
+        let config = config_from(&["--resolver", &upstream, "--upstream-timeout-ms", "100"]);
+
+        let started = Instant::now();
+        let response = forward(&query_for("example.com", 1), &config);
+
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(matches!(
+            response.header.r_code,
+            ResponseCode::ServerFailure
+        ));
+        assert!(response.answers.is_empty());
+        assert_eq!(response.questions[0].name, "example.com");
+    }
+
+    #[test]
+    fn client_packet_during_forwarding_is_not_consumed_by_upstream_exchange() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        let upstream = spawn_upstream(move |query| {
+            let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let pending = query_for("client.example", 1).to_bytes().unwrap();
+            client.send_to(&pending, listen_addr).unwrap();
+
+            answered(query, 60)
+        })
+        .to_string();
+        let config = config_from(&["--resolver", &upstream]);
+
+        let response = forward(&query_for("example.com", 1), &config);
+
+        let mut buf = [0; 512];
+        listener
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let (size, _) = listener.recv_from(&mut buf).unwrap();
+        let pending = DnsMessage::deserialize(&buf[..size]).unwrap();
+
+        assert_eq!(response.answers[0].data, [1, 2, 3, 4]);
+        assert_eq!(pending.questions[0].name, "client.example");
+    }
+
+    #[test]
+    fn reply_echoing_0x20_case_is_accepted_and_restored() {
+        let upstream = upstream_answering(60).to_string();
+        let config = config_from(&["--resolver", &upstream, "--use-0x20"]);
+
+        let response = forward(&query_for("example.com", 1), &config);
+
+        assert!(matches!(response.header.r_code, ResponseCode::NoError));
+        assert_eq!(response.questions[0].name, "example.com");
+        assert_eq!(response.answers[0].name, "example.com");
+    }
+
+    #[test]
+    fn reply_with_mismatched_0x20_case_is_rejected() {
+        let upstream = spawn_upstream(|mut query| {
+            let swapped = query.questions[0]
+                .name
+                .chars()
+                .map(|c| match c.is_ascii_lowercase() {
+                    true => c.to_ascii_uppercase(),
+                    false => c.to_ascii_lowercase(),
+                })
+                .collect();
+            query.questions[0].name = swapped;
+
+            answered(query, 60)
+        })
+        .to_string();
+        let config = config_from(&["--resolver", &upstream, "--use-0x20"]);
+
+        let response = forward(&query_for("example.com", 1), &config);
+
+        assert!(matches!(
+            response.header.r_code,
+            ResponseCode::ServerFailure
+        ));
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn query_with_too_many_questions_yields_formerr() {
+        let (upstream, forwarded) = spawn_counting_upstream(60);
+        let config = config_from(&["--resolver", &upstream.to_string()]);
+
+        let mut query = query_for("a.example", 1);
+        for _ in 0..MAX_QUESTIONS {
+            query
+                .questions
+                .push(Question::new("b.example".to_owned(), 1, 1));
+        }
+
+        let response = forward(&query, &config);
+
+        assert!(matches!(response.header.r_code, ResponseCode::FormatError));
+        assert!(forwarded.try_recv().is_err());
+    }
+
+    #[test]
+    fn multiple_questions_are_forwarded_concurrently() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstream = socket.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+
+            while let Ok((size, source)) = socket.recv_from(&mut buf) {
+                let query = DnsMessage::deserialize(&buf[..size]).unwrap();
+                let socket = socket.try_clone().unwrap();
+
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(300));
+                    socket
+                        .send_to(&answered(query, 60).to_bytes().unwrap(), source)
+                        .unwrap();
+                });
+            }
+        });
+        let config = config_from(&["--resolver", &upstream]);
+
+        let mut query = query_for("a.example", 1);
+        query
+            .questions
+            .push(Question::new("b.example".to_owned(), 1, 1));
+        query
+            .questions
+            .push(Question::new("c.example".to_owned(), 1, 1));
+
+        let started = Instant::now();
+        let response = forward(&query, &config);
+
+        assert!(started.elapsed() < Duration::from_millis(600));
+        let names: Vec<&str> = response.questions.iter().map(|q| q.name.as_str()).collect();
+        assert_eq!(names, ["a.example", "b.example", "c.example"]);
+        assert_eq!(response.answers.len(), 3);
+    }
+
+    #[test]
+    fn forwarded_response_counts_reflect_merged_answers() {
+        let upstream = spawn_upstream(|query| {
+            let mut response = answered(query, 60);
+            let name = response.answers[0].name.clone();
+            response
+                .answers
+                .push(Answer::new(name, 1, 1, 60, 4, vec![5, 6, 7, 8]));
+            response.header.an_count = 2;
+            response
+        })
+        .to_string();
+        let config = config_from(&["--resolver", &upstream]);
+
+        let response = forward(&query_for("example.com", 1), &config);
+
+        assert_eq!(response.header.an_count, 2);
+        assert_eq!(response.answers.len(), 2);
+    }
+
+    #[test]
+    fn cached_answer_is_served_until_ttl_elapses() {
+        let (upstream, forwarded) = spawn_counting_upstream(1);
+        let config = config_from(&["--resolver", &upstream.to_string(), "--cache"]);
+
+        let first = forward(&query_for("example.com", 1), &config);
+        let second = forward(&query_for("example.com", 1), &config);
+        assert_eq!(forwarded.try_iter().count(), 1);
+
+        thread::sleep(Duration::from_millis(1100));
+        forward(&query_for("example.com", 1), &config);
+
+        assert_eq!(first.answers[0].data, second.answers[0].data);
+        assert!(second.answers[0].time_to_live <= 1);
+        assert_eq!(forwarded.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn truncated_upstream_reply_is_flagged_and_not_cached() {
+        let (sender, forwarded) = mpsc::channel();
+        let upstream = spawn_upstream(move |query| {
+            sender.send(()).unwrap();
+            let mut response = answered(query, 60);
+            response.header.is_trunc = true;
+            response
+        })
+        .to_string();
+        let config = config_from(&["--resolver", &upstream, "--cache"]);
+
+        let first = forward(&query_for("example.com", 1), &config);
+        forward(&query_for("example.com", 1), &config);
+
+        assert!(first.header.is_trunc);
+        assert_eq!(forwarded.try_iter().count(), 2);
+    }
+
+    #[test]
+    fn no_cache_name_is_forwarded_on_every_query() {
+        let (upstream, forwarded) = spawn_counting_upstream(60);
+        let upstream = upstream.to_string();
+        let config = config_from(&[
+            "--resolver",
+            &upstream,
+            "--cache",
+            "--no-cache",
+            "dyn.example.com.",
+        ]);
+
+        for _ in 0..2 {
+            forward(&query_for("DYN.example.com", 1), &config);
+        }
+        assert_eq!(forwarded.try_iter().count(), 2);
+
+        for _ in 0..2 {
+            forward(&query_for("example.com", 1), &config);
+        }
+        assert_eq!(forwarded.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn forwarded_authority_records_are_preserved() {
+        let upstream = spawn_upstream(|query| {
+            let mut response = answered(query, 60);
+            let ns = encode_name("ns1.example.com").unwrap();
+            response.authorities.push(Answer::new(
+                "example.com".to_owned(),
+                2,
+                1,
+                3600,
+                ns.len() as u16,
+                ns,
+            ));
+            response.header.ns_count = 1;
+            response
+        })
+        .to_string();
+        let config = config_from(&["--resolver", &upstream]);
+
+        let response = forward(&query_for("example.com", 1), &config);
+
+        assert_eq!(response.header.ns_count, 1);
+        assert_eq!(response.authorities.len(), 1);
+        assert_eq!(
+            response.authorities[0].data,
+            encode_name("ns1.example.com").unwrap()
+        );
+    }
+
+    #[test]
+    fn forwarded_glue_records_are_preserved() {
+        let upstream = spawn_upstream(|query| {
+            let mut response = answered(query, 60);
+            response.additionals.push(Answer::new(
+                "ns1.example.com".to_owned(),
+                1,
+                1,
+                3600,
+                4,
+                vec![192, 0, 2, 1],
+            ));
+            response
+                .additionals
+                .push(Answer::new(String::new(), TYPE_OPT, 1232, 0, 0, Vec::new()));
+            response.fix_counts();
+            response
+        })
+        .to_string();
+        let config = config_from(&["--resolver", &upstream]);
+
+        let response = forward(&query_for("example.com", 1), &config);
+
+        assert_eq!(response.header.ar_count, 1);
+        assert_eq!(response.additionals[0].name, "ns1.example.com");
+        assert_eq!(response.additionals[0].data, [192, 0, 2, 1]);
+    }
+
+    #[test]
+    fn forwarded_query_uses_generated_packet_id() {
+        // Captures the datagram exactly as received rather than re-encoding
+        // the parsed query, so padding or trailing bytes would show up.
+        let (sender, receiver) = mpsc::channel();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstream = socket.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (size, source) = socket.recv_from(&mut buf).unwrap();
+            sender.send(buf[..size].to_vec()).unwrap();
+
+            let query = DnsMessage::deserialize(&buf[..size]).unwrap();
+            let reply = answered(query, 60).to_bytes().unwrap();
+            socket.send_to(&reply, source).unwrap();
+        });
+        let config = config_from(&["--resolver", &upstream])
+            .with_id_generator(SequentialIdGenerator::new(0xABCD));
+
+        let response = forward(&query_for("ab.c", 1), &config);
+        let forwarded = receiver.recv().unwrap();
+
+        assert_eq!(
+            forwarded,
+            [
+                0xAB, 0xCD, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, b'a',
+                b'b', 0x01, b'c', 0x00, 0x00, 0x01, 0x00, 0x01
+            ]
+        );
+        assert_eq!(response.header.packet_id, 1234);
+    }
+
+    #[test]
+    fn rewritten_query_is_forwarded_and_answered_under_original_name() {
+        let (sender, receiver) = mpsc::channel();
+        let upstream = spawn_upstream(move |query| {
+            sender.send(query.question().unwrap().name.clone()).unwrap();
+
+            answered(query, 60)
+        })
+        .to_string();
+        let config = config_from(&[
+            "--resolver",
+            &upstream,
+            "--rewrite",
+            "old.example.com=new.example.com",
+        ]);
+
+        let response = forward(&query_for("old.example.com", 1), &config);
+
+        assert_eq!(receiver.recv().unwrap(), "new.example.com");
+        assert_eq!(response.questions[0].name, "old.example.com");
+        assert_eq!(response.answers[0].name, "old.example.com");
+    }
+
+    #[test]
+    fn rewritten_names_stay_on_their_question_when_upstream_echoes_none() {
+        let upstream = spawn_upstream(|query| {
+            let name = query.question().unwrap().name.clone();
+            let mut response = answered(query, 60);
+            if name == "new.example.com" {
+                response.header.r_code = ResponseCode::FormatError;
+                response.questions.clear();
+                response.answers.clear();
+                response.fix_counts();
+            }
+            response
+        })
+        .to_string();
+        let config = config_from(&[
+            "--resolver",
+            &upstream,
+            "--rewrite",
+            "old.example.com=new.example.com",
+        ]);
+        let mut query = query_for("old.example.com", 1);
+        query
+            .questions
+            .push(Question::new("other.com".to_owned(), 1, 1));
+        query.fix_counts();
+
+        let response = forward(&query, &config);
+
+        let names: Vec<&str> = response.questions.iter().map(|q| q.name.as_str()).collect();
+        assert_eq!(names, ["old.example.com", "other.com"]);
+        assert_eq!(response.answers[0].name, "other.com");
+    }
+
+    #[test]
+    fn rewrite_chain_stops_at_max_depth() {
+        let rewrites: HashMap<String, String> = (0..10)
+            .map(|i| (format!("n{i}.com"), format!("n{}.com", i + 1)))
+            .collect();
+
+        assert_eq!(rewrite_name("n0.com", &rewrites), "n8.com");
+        assert_eq!(rewrite_name("c.com", &rewrites), "c.com");
+    }
+
+    #[test]
+    fn shuffle_with_fixed_seed_reorders_each_rrset() {
+        let mut answers: Vec<Answer> = (1..=4)
+            .map(|i| Answer::new("a.com".to_owned(), 1, 1, 60, 4, vec![10, 0, 0, i]))
+            .collect();
+        answers.insert(
+            2,
+            Answer::new("b.com".to_owned(), 1, 1, 60, 4, vec![10, 0, 1, 1]),
+        );
+
+        shuffle_answers(&mut answers, &mut Rng::new(7));
+
+        let order: Vec<u8> = answers.iter().map(|a| a.data[3]).collect();
+        assert_eq!(order, [2, 3, 1, 1, 4]);
+        assert_eq!(answers[2].name, "b.com");
+    }
+
+    fn handle(query: &DnsMessage, config: &DnsServerConfig) -> Option<DnsMessage> {
+        let source = "127.0.0.1:5300".parse().unwrap();
+
+        handle_packet(&query.to_bytes().unwrap(), source, config)
+    }
+
+    #[test]
+    fn interleaved_rrsets_are_grouped_in_order_of_first_appearance() {
+        let mut answers = vec![
+            Answer::new("example.com".to_string(), 1, 1, 60, 4, vec![1, 1, 1, 1]),
+            Answer::new("example.com".to_string(), 15, 1, 60, 4, vec![0, 10, 0, 0]),
+            Answer::new("example.com".to_string(), 1, 1, 60, 4, vec![2, 2, 2, 2]),
+            Answer::new("example.com".to_string(), 15, 1, 60, 4, vec![0, 20, 0, 0]),
+        ];
+
+        group_rrsets(&mut answers);
+
+        let layout: Vec<(u16, u8)> = answers.iter().map(|a| (a.record_type, a.data[1])).collect();
+        assert_eq!(layout, [(1, 1), (1, 2), (15, 10), (15, 20)]);
+    }
+
+    #[test]
+    fn query_from_allowlisted_source_is_answered() {
+        let config = config_from(&["--allow", "10.0.0.0/8", "--allow", "127.0.0.0/8"]);
+
+        let response = handle(&query_for("example.com", 1), &config).unwrap();
+
+        assert!(matches!(response.header.r_code, ResponseCode::NoError));
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[test]
+    fn query_from_denied_source_is_refused() {
+        let config = config_from(&["--allow", "10.0.0.0/8"]);
+
+        let response = handle(&query_for("example.com", 1), &config).unwrap();
+
+        assert!(matches!(response.header.r_code, ResponseCode::Refused));
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn query_from_denied_source_is_dropped_when_configured() {
+        let config = config_from(&["--allow", "10.0.0.0/8", "--deny-action", "drop"]);
+
+        assert!(handle(&query_for("example.com", 1), &config).is_none());
+    }
+
+    #[test]
+    fn rrset_ttls_are_normalized_to_minimum() {
+        let mut answers: Vec<Answer> = [100, 60, 200]
+            .into_iter()
+            .map(|ttl| Answer::new("a.com".to_owned(), 1, 1, ttl, 4, vec![10, 0, 0, 1]))
+            .collect();
+        answers.push(Answer::new(
+            "b.com".to_owned(),
+            1,
+            1,
+            300,
+            4,
+            vec![10, 0, 0, 2],
+        ));
+
+        normalize_rrset_ttls(&mut answers);
+
+        let ttls: Vec<u32> = answers.iter().map(|a| a.time_to_live).collect();
+        assert_eq!(ttls, [60, 60, 60, 300]);
+    }
+
+    #[test]
+    fn localhost_is_answered_locally_without_forwarding() {
+        let (upstream, forwarded) = spawn_counting_upstream(60);
+        let upstream = upstream.to_string();
+        let config = config_from(&["--resolver", &upstream]);
+
+        let response = forward(&query_for("api.localhost", 1), &config);
+
+        assert_eq!(response.answers[0].data, [127, 0, 0, 1]);
+        assert!(forwarded.try_recv().is_err());
+    }
+
+    #[test]
+    fn localhost_aaaa_is_answered_with_ipv6_loopback() {
+        let config = config_from(&[]);
+        let question = Question::new("localhost".to_owned(), 28, 1);
+
+        let (_, answers) = local_response(&question, &config).unwrap();
+
+        assert_eq!(answers[0].data, Ipv6Addr::LOCALHOST.octets());
+    }
+
+    #[test]
+    fn localhost_is_resolved_normally_when_disabled() {
+        let config = config_from(&["--no-localhost"]);
+
+        let response = handle(&query_for("localhost", 1), &config).unwrap();
+
+        assert_eq!(response.answers[0].data, [8, 8, 8, 8]);
+    }
+
+    #[test]
+    fn loopback_ptr_is_answered_locally_without_forwarding() {
+        let (upstream, forwarded) = spawn_counting_upstream(60);
+        let upstream = upstream.to_string();
+        let config = config_from(&["--resolver", &upstream]);
+
+        let response = forward(&query_for("1.0.0.127.in-addr.arpa", 12), &config);
+        let ipv6 = ipv6_reverse_name(&Ipv6Addr::LOCALHOST).to_ascii_uppercase();
+        let ipv6_response = forward(&query_for(&ipv6, 12), &config);
+
+        assert_eq!(response.answers[0].data, encode_name("localhost").unwrap());
+        assert_eq!(
+            ipv6_response.answers[0].data,
+            encode_name("localhost").unwrap()
+        );
+        assert!(forwarded.try_recv().is_err());
+    }
+
+    #[test]
+    fn non_address_loopback_reverse_names_do_not_exist() {
+        let config = config_from(&[]);
+
+        for name in [
+            "a.b.c.127.in-addr.arpa",
+            "1.1.0.0.127.in-addr.arpa",
+            "256.0.0.127.in-addr.arpa",
+        ] {
+            let response = handle(&query_for(name, 12), &config).unwrap();
+
+            assert!(matches!(response.header.r_code, ResponseCode::NameError));
+            assert!(response.answers.is_empty());
+        }
+
+        let partial = handle(&query_for("0.127.in-addr.arpa", 12), &config).unwrap();
+
+        assert!(matches!(partial.header.r_code, ResponseCode::NoError));
+        assert!(partial.answers.is_empty());
+    }
+
+    #[test]
+    fn loopback_ptr_is_resolved_normally_when_disabled() {
+        let config = config_from(&["--no-loopback-reverse"]);
+        let question = Question::new("1.0.0.127.in-addr.arpa".to_owned(), 12, 1);
+
+        assert!(local_response(&question, &config).is_none());
+    }
+
+    #[test]
+    fn invalid_domain_returns_name_error_without_forwarding() {
+        let (upstream, forwarded) = spawn_counting_upstream(60);
+        let upstream = upstream.to_string();
+        let config = config_from(&["--resolver", &upstream]);
+
+        let response = forward(&query_for("foo.invalid", 1), &config);
+
+        assert!(matches!(response.header.r_code, ResponseCode::NameError));
+        assert!(response.answers.is_empty());
+        assert!(forwarded.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_domain_follows_configured_action() {
+        let upstream = upstream_answering(60).to_string();
+        let default_config = config_from(&["--resolver", &upstream]);
+        let resolve_config =
+            config_from(&["--resolver", &upstream, "--special-use", "test=resolve"]);
+
+        let default_response = forward(&query_for("foo.test", 1), &default_config);
+        let resolved_response = forward(&query_for("foo.test", 1), &resolve_config);
+
+        assert!(matches!(
+            default_response.header.r_code,
+            ResponseCode::NameError
+        ));
+        assert!(matches!(
+            resolved_response.header.r_code,
+            ResponseCode::NoError
+        ));
+        assert_eq!(resolved_response.answers.len(), 1);
+    }
+
+    #[test]
+    fn disabled_type_returns_no_data_while_others_resolve() {
+        let config = config_from(&["--disable-type", "AAAA"]);
+
+        let aaaa_response = handle(&query_for("example.com", 28), &config).unwrap();
+        let a_response = handle(&query_for("example.com", 1), &config).unwrap();
+
+        assert!(matches!(aaaa_response.header.r_code, ResponseCode::NoError));
+        assert!(aaaa_response.answers.is_empty());
+        assert_eq!(a_response.answers.len(), 1);
+    }
+
+    #[test]
+    fn truncation_flag_from_query_is_not_echoed() {
+        let mut query = query_for("example.com", 1);
+        query.header.is_trunc = true;
+
+        let response = handle(&query, &config_from(&[])).unwrap();
+
+        assert!(!response.header.is_trunc);
+    }
+
+    #[test]
+    fn aaaa_nodata_mode_answers_aaaa_with_no_data_without_forwarding() {
+        let (upstream, forwarded) = spawn_counting_upstream(60);
+        let upstream = upstream.to_string();
+        let config = config_from(&["--resolver", &upstream, "--aaaa-nodata"]);
+
+        let aaaa_response = forward(&query_for("example.com", 28), &config);
+        assert!(forwarded.try_recv().is_err());
+
+        let a_response = forward(&query_for("example.com", 1), &config);
+
+        assert!(matches!(aaaa_response.header.r_code, ResponseCode::NoError));
+        assert!(aaaa_response.answers.is_empty());
+        assert_eq!(a_response.answers.len(), 1);
+    }
+
+    struct MockUpstream;
+
+    impl Upstream for MockUpstream {
+        fn query(&self, msg: &DnsMessage) -> Result<DnsMessage, DnsError> {
+            let questions = msg
+                .questions
+                .iter()
+                .map(|q| Question::new(q.name.clone(), q.record_type, q.class))
+                .collect();
+            let answer = Answer::new("canned.com".to_owned(), 1, 1, 60, 4, vec![9, 9, 9, 9]);
+
+            Ok(DnsMessage::new(msg.header, questions, vec![answer]))
+        }
+    }
+
+    #[test]
+    fn single_query_is_forwarded_through_upstream() {
+        let config = config_from(&[]);
+        let question = Question::new("example.com".to_owned(), 1, 1);
+
+        let reply = handle_single_query_fwd(question, &MockUpstream, &config);
+
+        assert_eq!(reply.questions[0].name, "example.com");
+        assert_eq!(reply.answers[0].name, "canned.com");
+        assert_eq!(reply.answers[0].data, [9, 9, 9, 9]);
+    }
+
+    #[test]
+    fn packet_shorter_than_header_is_skipped_without_response() {
+        let source = "127.0.0.1:5300".parse().unwrap();
+        let config = config_from(&[]);
+
+        let query = query_for("example.com", 1).to_bytes().unwrap();
+        let response = handle_packet(&query[..10], source, &config);
+
+        assert!(response.is_none());
+    }
+
+    #[test]
+    fn response_packets_are_dropped_even_when_malformed() {
+        let source = "127.0.0.1:5300".parse().unwrap();
+        let config = config_from(&[]);
+
+        let mut response = query_for("example.com", 1).to_bytes().unwrap();
+        response[2] |= 0x80;
+        let mut malformed = response.clone();
+        malformed[5] = 5;
+
+        assert!(handle_packet(&response, source, &config).is_none());
+        assert!(handle_packet(&malformed, source, &config).is_none());
+    }
+
+    #[test]
+    fn question_count_beyond_packet_yields_formerr() {
+        let source = "127.0.0.1:5300".parse().unwrap();
+        let config = config_from(&[]);
+
+        let mut query = query_for("example.com", 1).to_bytes().unwrap();
+        query[5] = 5;
+        let response = handle_packet(&query, source, &config).unwrap();
+
+        assert!(matches!(response.header.r_code, ResponseCode::FormatError));
+        assert!(response.is_response());
+        assert_eq!(response.header.packet_id, 1234);
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn concurrent_udp_queries_are_answered_despite_slow_upstream() {
+        // Holds back the reply to "slow" until "fast" has been answered, so
+        // a single worker would never answer either query.
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            let mut held = None;
+            let mut fast_answered = false;
+
+            while let Ok((size, source)) = upstream.recv_from(&mut buf) {
+                let query = DnsMessage::deserialize(&buf[..size]).unwrap();
+
+                if query.question().unwrap().name == "slow.example.com" && !fast_answered {
+                    held = Some((query, source));
+                    continue;
+                }
+
+                fast_answered = true;
+                let reply = answered(query, 60).to_bytes().unwrap();
+                upstream.send_to(&reply, source).unwrap();
+
+                if let Some((query, source)) = held.take() {
+                    let reply = answered(query, 60).to_bytes().unwrap();
+                    upstream.send_to(&reply, source).unwrap();
+                }
+            }
+        });
+
+        let args = ["--resolver", upstream_addr.as_str(), "--workers", "2"];
+        let config: &'static DnsServerConfig = Box::leak(Box::new(config_from(&args)));
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let _ = serve_udp(&server, config);
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        for (id, name) in [(1, "slow.example.com"), (2, "fast.example.com")] {
+            let mut query = query_for(name, 1);
+            query.header.packet_id = id;
+            client
+                .send_to(&query.to_bytes().unwrap(), server_addr)
+                .unwrap();
+        }
+
+        let mut buf = [0; 512];
+        let mut ids: Vec<u16> = (0..2)
+            .map(|_| {
+                let (size, _) = client.recv_from(&mut buf).unwrap();
+                DnsMessage::deserialize(&buf[..size])
+                    .unwrap()
+                    .header
+                    .packet_id
+            })
+            .collect();
+        ids.sort();
+
+        assert_eq!(ids, [1, 2]);
+    }
+
+    #[test]
+    fn tcp_query_is_answered_with_length_prefixed_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = config_from(&["--tcp"]);
+
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        tcp::write_message(
+            &mut client,
+            &query_for("example.com", 1).to_bytes().unwrap(),
+        )
+        .unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        handle_tcp_connection(stream, &config).unwrap();
+
+        let response = tcp::read_message(&mut client).unwrap();
+        let response = DnsMessage::deserialize(&response).unwrap();
+
+        assert_eq!(response.header.packet_id, 1234);
+        assert_eq!(response.answers[0].data, [8, 8, 8, 8]);
+    }
+
+    #[test]
+    fn idle_tcp_connection_does_not_block_other_clients() {
+        let config: &'static DnsServerConfig = Box::leak(Box::new(config_from(&["--tcp"])));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve_tcp(listener, config));
+
+        let _idle = TcpStream::connect(server_addr).unwrap();
+        let mut client = TcpStream::connect(server_addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        tcp::write_message(
+            &mut client,
+            &query_for("example.com", 1).to_bytes().unwrap(),
+        )
+        .unwrap();
+
+        let response = tcp::read_message(&mut client).unwrap();
+        let response = DnsMessage::deserialize(&response).unwrap();
+
+        assert_eq!(response.header.packet_id, 1234);
+    }
+
+    #[test]
+    fn pipelined_tcp_queries_are_each_answered() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = config_from(&["--tcp"]);
+
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut second = query_for("example.org", 1);
+        second.header.packet_id = 4321;
+        tcp::write_message(
+            &mut client,
+            &query_for("example.com", 1).to_bytes().unwrap(),
+        )
+        .unwrap();
+        tcp::write_message(&mut client, &second.to_bytes().unwrap()).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        handle_tcp_connection(stream, &config).unwrap();
+
+        let responses: Vec<DnsMessage> = (0..2)
+            .map(|_| DnsMessage::deserialize(&tcp::read_message(&mut client).unwrap()).unwrap())
+            .collect();
+
+        assert_eq!(responses[0].header.packet_id, 1234);
+        assert_eq!(responses[0].questions[0].name, "example.com");
+        assert_eq!(responses[1].header.packet_id, 4321);
+        assert_eq!(responses[1].questions[0].name, "example.org");
+    }
+
+    #[test]
+    fn tcp_connection_is_closed_after_max_queries() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = config_from(&["--tcp"]);
+
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let query = query_for("example.com", 1).to_bytes().unwrap();
+        for _ in 0..MAX_TCP_QUERIES {
+            tcp::write_message(&mut client, &query).unwrap();
+        }
+
+        let (stream, _) = listener.accept().unwrap();
+        let started = Instant::now();
+        handle_tcp_connection(stream, &config).unwrap();
+
+        // The client never closed its side, so only the query limit ends it.
+        assert!(started.elapsed() < TCP_IDLE_TIMEOUT);
+
+        for _ in 0..MAX_TCP_QUERIES {
+            tcp::read_message(&mut client).unwrap();
+        }
+        assert!(tcp::read_message(&mut client).is_err());
+    }
+
+    #[test]
+    fn edns_query_gets_opt_record_with_negotiated_payload_size() {
+        let source = "127.0.0.1:5300".parse().unwrap();
+        let config = config_from(&["--max-udp-size", "1232"]);
+
+        let mut query = query_for("example.com", 1).to_bytes().unwrap();
+        query[11] = 1;
+        query.extend([0x0, 0x0, 0x29, 0x10, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0]);
+
+        let response = handle_packet(&query, source, &config).unwrap();
+
+        assert_eq!(response.edns_payload_size(), Some(1232));
+        assert_eq!(response.answers[0].data, [8, 8, 8, 8]);
+    }
+
+    #[test]
+    fn oversized_udp_response_falls_back_to_header_with_tc() {
+        let config = config_from(&["--no-edns-udp-size", "20"]);
+        let response = answered(query_for(&"a".repeat(40), 1), 60);
+
+        let serialized = udp_response_bytes(&response, config.no_edns_udp_size, &config).unwrap();
+        let parsed = DnsMessage::deserialize(&serialized).unwrap();
+
+        assert_eq!(serialized.len(), 12);
+        assert!(parsed.header.is_trunc);
+        assert_eq!(parsed.header.packet_id, 1234);
+    }
+
+    #[test]
+    fn oversized_udp_response_is_dropped_when_configured() {
+        let config = config_from(&["--no-edns-udp-size", "20", "--oversized-action", "drop"]);
+        let response = answered(query_for(&"a".repeat(40), 1), 60);
+
+        assert!(udp_response_bytes(&response, config.no_edns_udp_size, &config).is_none());
+    }
+
+    #[test]
+    fn unencodable_response_is_answered_with_format_error() {
+        let config = config_from(&[]);
+        let response = query_for(&format!("{}.com", "a".repeat(64)), 1);
+
+        let serialized = udp_response_bytes(&response, config.no_edns_udp_size, &config).unwrap();
+        let parsed = DnsMessage::deserialize(&serialized).unwrap();
 
-        DnsServerConfig { bind_addr, mode }
+        assert!(matches!(parsed.header.r_code, ResponseCode::FormatError));
+        assert_eq!(parsed.header.packet_id, 1234);
+        assert!(parsed.questions.is_empty());
     }
 }
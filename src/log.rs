@@ -0,0 +1,117 @@
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "error" => Some(Self::Error),
+            "warn" => Some(Self::Warn),
+            "info" => Some(Self::Info),
+            "debug" => Some(Self::Debug),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Error => "ERROR",
+            Self::Warn => "WARN",
+            Self::Info => "INFO",
+            Self::Debug => "DEBUG",
+        }
+    }
+}
+
+pub trait LogSink: Send + Sync {
+    fn write(&self, level: LogLevel, line: &str);
+}
+
+pub struct StdioSink;
+
+impl LogSink for StdioSink {
+    fn write(&self, level: LogLevel, line: &str) {
+        match level {
+            LogLevel::Error | LogLevel::Warn => eprintln!("{}", line),
+            LogLevel::Info | LogLevel::Debug => println!("{}", line),
+        }
+    }
+}
+
+pub struct Logger {
+    level: LogLevel,
+    sink: Box<dyn LogSink>,
+}
+
+impl Logger {
+    pub fn new(level: LogLevel, sink: impl LogSink + 'static) -> Self {
+        Self {
+            level,
+            sink: Box::new(sink),
+        }
+    }
+
+    pub fn level(&self) -> LogLevel {
+        self.level
+    }
+
+    pub fn enabled(&self, level: LogLevel) -> bool {
+        level <= self.level
+    }
+
+    pub fn log(&self, level: LogLevel, args: fmt::Arguments) {
+        if self.enabled(level) {
+            self.sink
+                .write(level, &format!("[{}] {}", level.as_str(), args));
+        }
+    }
+
+    pub fn error(&self, args: fmt::Arguments) {
+        self.log(LogLevel::Error, args);
+    }
+
+    pub fn warn(&self, args: fmt::Arguments) {
+        self.log(LogLevel::Warn, args);
+    }
+
+    pub fn info(&self, args: fmt::Arguments) {
+        self.log(LogLevel::Info, args);
+    }
+
+    pub fn debug(&self, args: fmt::Arguments) {
+        self.log(LogLevel::Debug, args);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct MemorySink(Arc<Mutex<Vec<String>>>);
+
+    impl LogSink for MemorySink {
+        fn write(&self, _level: LogLevel, line: &str) {
+            self.0.lock().unwrap().push(line.to_owned());
+        }
+    }
+
+    #[test]
+    fn messages_above_configured_level_are_filtered() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let logger = Logger::new(LogLevel::Warn, MemorySink(lines.clone()));
+
+        logger.error(format_args!("a"));
+        logger.warn(format_args!("b"));
+        logger.info(format_args!("c"));
+        logger.debug(format_args!("d"));
+
+        assert_eq!(*lines.lock().unwrap(), ["[ERROR] a", "[WARN] b"]);
+    }
+}
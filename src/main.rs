@@ -5,7 +5,10 @@ use codecrafters_dns_server::{DnsServerConfig, run_dns_server};
 fn main() {
     println!("Logs from your program will appear here!");
 
-    let config = DnsServerConfig::new(env::args());
+    let config = DnsServerConfig::new(env::args()).unwrap_or_else(|err| {
+        eprintln!("Problem parsing arguments: {err}");
+        process::exit(1);
+    });
 
     if let Err(err) = run_dns_server(&config) {
         eprintln!("Application error: {err}");
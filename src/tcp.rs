@@ -0,0 +1,21 @@
+use std::io::{self, Read, Write};
+
+pub fn read_message(stream: &mut impl Read) -> Result<Vec<u8>, io::Error> {
+    let mut len_buf = [0; 2];
+    stream.read_exact(&mut len_buf)?;
+
+    let mut msg = vec![0; u16::from_be_bytes(len_buf) as usize];
+    stream.read_exact(&mut msg)?;
+
+    Ok(msg)
+}
+
+pub fn write_message(stream: &mut impl Write, msg: &[u8]) -> Result<(), io::Error> {
+    let len: u16 = msg
+        .len()
+        .try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too long for TCP"))?;
+
+    stream.write_all(&len.to_be_bytes())?;
+    stream.write_all(msg)
+}
@@ -0,0 +1,231 @@
+use crate::dns_message::DnsMessage;
+use crate::error::DnsError;
+use crate::tcp;
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+pub trait Upstream {
+    fn query(&self, msg: &DnsMessage) -> Result<DnsMessage, DnsError>;
+}
+
+pub struct UdpUpstream<'a> {
+    resolver_addr: &'a str,
+    timeout: Duration,
+}
+
+impl<'a> UdpUpstream<'a> {
+    pub fn new(resolver_addr: &'a str, timeout: Duration) -> Self {
+        Self {
+            resolver_addr,
+            timeout,
+        }
+    }
+}
+
+impl Upstream for UdpUpstream<'_> {
+    fn query(&self, msg: &DnsMessage) -> Result<DnsMessage, DnsError> {
+        // Large enough for any datagram, whatever EDNS size is in play.
+        let mut buf = vec![0; u16::MAX as usize];
+        let resolver = resolve(self.resolver_addr)?;
+
+        // A fresh ephemeral socket per exchange keeps resolver replies apart
+        // from client traffic and from concurrent exchanges.
+        let local: SocketAddr = match resolver {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.send_to(&msg.to_bytes()?, resolver)?;
+
+        let deadline = Instant::now() + self.timeout;
+        let packet_id = msg.header.packet_id.to_be_bytes();
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::from(io::ErrorKind::TimedOut).into());
+            }
+            socket.set_read_timeout(Some(remaining))?;
+
+            let (size, source) = socket.recv_from(&mut buf)?;
+
+            // Datagrams from elsewhere or for another ID are stale or spoofed.
+            if source != resolver || !buf[..size].starts_with(&packet_id) {
+                continue;
+            }
+
+            let reply = DnsMessage::deserialize(&buf[..size])?;
+            if !reply.header.is_trunc {
+                return Ok(reply);
+            }
+
+            // Retry a truncated reply over TCP, falling back to the truncated
+            // reply (which keeps its TC bit) if that fails too.
+            let tcp = TcpUpstream::new(self.resolver_addr, remaining);
+            return Ok(tcp.query(msg).unwrap_or(reply));
+        }
+    }
+}
+
+fn resolve(addr: &str) -> io::Result<SocketAddr> {
+    addr.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("resolver address {addr:?} did not resolve"),
+        )
+    })
+}
+
+pub struct TcpUpstream<'a> {
+    resolver_addr: &'a str,
+    timeout: Duration,
+}
+
+impl<'a> TcpUpstream<'a> {
+    pub fn new(resolver_addr: &'a str, timeout: Duration) -> Self {
+        Self {
+            resolver_addr,
+            timeout,
+        }
+    }
+}
+
+impl Upstream for TcpUpstream<'_> {
+    fn query(&self, msg: &DnsMessage) -> Result<DnsMessage, DnsError> {
+        let resolver = resolve(self.resolver_addr)?;
+        let mut stream = TcpStream::connect_timeout(&resolver, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+
+        tcp::write_message(&mut stream, &msg.to_bytes()?)?;
+        let reply = tcp::read_message(&mut stream)?;
+
+        Ok(DnsMessage::deserialize(&reply)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_message::{Answer, Question};
+    use crate::id_generator::SequentialIdGenerator;
+
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn udp_upstream_ignores_replies_with_wrong_source_or_id() {
+        let resolver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = resolver.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (size, client) = resolver.recv_from(&mut buf).unwrap();
+            let query = DnsMessage::deserialize(&buf[..size]).unwrap();
+
+            let reply = |id: u16, data: Vec<u8>| {
+                let mut reply = DnsMessage::deserialize(&buf[..size]).unwrap();
+                let name = reply.questions[0].name.clone();
+                reply.header.packet_id = id;
+                reply.answers.push(Answer::new(name, 1, 1, 60, 4, data));
+                reply.to_bytes().unwrap()
+            };
+
+            let spoofer = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let id = query.header.packet_id;
+            spoofer
+                .send_to(&reply(id, vec![6, 6, 6, 6]), client)
+                .unwrap();
+            resolver
+                .send_to(&reply(id + 1, vec![6, 6, 6, 6]), client)
+                .unwrap();
+            resolver
+                .send_to(&reply(id, vec![1, 2, 3, 4]), client)
+                .unwrap();
+        });
+
+        let query = DnsMessage::new_query(
+            &SequentialIdGenerator::new(7),
+            vec![Question::new("a.com".to_owned(), 1, 1)],
+        );
+
+        let reply = UdpUpstream::new(&addr, Duration::from_secs(5))
+            .query(&query)
+            .unwrap();
+
+        assert_eq!(reply.header.packet_id, 7);
+        assert_eq!(reply.answers[0].data, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn udp_upstream_retries_truncated_reply_over_tcp() {
+        let resolver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = resolver.local_addr().unwrap();
+        let listener = TcpListener::bind(addr).unwrap();
+
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (size, client) = resolver.recv_from(&mut buf).unwrap();
+            let mut reply = DnsMessage::deserialize(&buf[..size]).unwrap();
+            reply.header.is_trunc = true;
+            resolver
+                .send_to(&reply.to_bytes().unwrap(), client)
+                .unwrap();
+
+            let (mut stream, _) = listener.accept().unwrap();
+            let query = tcp::read_message(&mut stream).unwrap();
+            let mut reply = DnsMessage::deserialize(&query).unwrap();
+            let name = reply.questions[0].name.clone();
+            reply
+                .answers
+                .push(Answer::new(name, 1, 1, 60, 4, vec![1, 2, 3, 4]));
+            tcp::write_message(&mut stream, &reply.to_bytes().unwrap()).unwrap();
+        });
+
+        let query = DnsMessage::new_query(
+            &SequentialIdGenerator::new(7),
+            vec![Question::new("a.com".to_owned(), 1, 1)],
+        );
+
+        let addr = addr.to_string();
+        let reply = UdpUpstream::new(&addr, Duration::from_secs(5))
+            .query(&query)
+            .unwrap();
+
+        assert!(!reply.header.is_trunc);
+        assert_eq!(reply.answers[0].data, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn tcp_upstream_frames_query_and_reply_with_length_prefix() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let query = tcp::read_message(&mut stream).unwrap();
+
+            let mut reply = DnsMessage::deserialize(&query).unwrap();
+            let name = reply.questions[0].name.clone();
+            reply
+                .answers
+                .push(Answer::new(name, 1, 1, 60, 4, vec![1, 2, 3, 4]));
+
+            tcp::write_message(&mut stream, &reply.to_bytes().unwrap()).unwrap();
+        });
+
+        let query = DnsMessage::new_query(
+            &SequentialIdGenerator::new(7),
+            vec![Question::new("a.com".to_owned(), 1, 1)],
+        );
+
+        let addr = addr.to_string();
+        let reply = TcpUpstream::new(&addr, Duration::from_secs(5))
+            .query(&query)
+            .unwrap();
+
+        assert_eq!(reply.header.packet_id, 7);
+        assert_eq!(reply.answers[0].data, [1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::dns_message::{encode_character_strings, encode_name, Answer};
+use crate::parse_record_type;
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use thiserror::Error;
+
+/// Static records served by the resolving server, keyed by lowercase owner
+/// name.
+pub struct Zone {
+    records: HashMap<String, Vec<Answer>>,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ZoneParseError {
+    #[error("line {0}: expected name, type, TTL and value")]
+    MissingField(usize),
+    #[error("line {0}: unsupported record type {1:?}")]
+    UnsupportedType(usize, String),
+    #[error("line {0}: invalid TTL")]
+    InvalidTtl(usize),
+    #[error("line {0}: invalid record value")]
+    InvalidValue(usize),
+}
+
+impl Zone {
+    /// Records of `record_type` owned by `name`, or `None` if the zone has
+    /// no records at all for `name`.
+    pub fn lookup(&self, name: &str, record_type: u16) -> Option<Vec<Answer>> {
+        let records = self.records.get(&Self::key(name))?;
+
+        let answers = records
+            .iter()
+            .filter(|record| record.record_type == record_type)
+            .cloned()
+            .map(|record| Answer {
+                name: name.to_owned(),
+                ..record
+            })
+            .collect();
+
+        Some(answers)
+    }
+
+    fn key(name: &str) -> String {
+        name.trim_matches('.').to_ascii_lowercase()
+    }
+
+    fn parse_rdata(record_type: u16, value: &str) -> Option<Vec<u8>> {
+        match record_type {
+            1 => value.parse::<Ipv4Addr>().ok().map(|a| a.octets().to_vec()),
+            28 => value.parse::<Ipv6Addr>().ok().map(|a| a.octets().to_vec()),
+            2 | 5 | 12 => encode_name(value.trim_end_matches('.')).ok(),
+            15 => {
+                let (preference, exchange) = value.split_once(char::is_whitespace)?;
+                let mut data = preference.parse::<u16>().ok()?.to_be_bytes().to_vec();
+                data.extend(encode_name(exchange.trim().trim_end_matches('.')).ok()?);
+                Some(data)
+            }
+            16 => Some(encode_character_strings(&[value.trim_matches('"')])),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Zone {
+    type Err = ZoneParseError;
+
+    /// Parses one record per line as `name type TTL value`. Blank lines and
+    /// lines starting with `;` are ignored.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut records: HashMap<String, Vec<Answer>> = HashMap::new();
+
+        for (index, line) in s.lines().enumerate() {
+            let line_no = index + 1;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [name, record_type, ttl, value @ ..] = fields.as_slice() else {
+                return Err(ZoneParseError::MissingField(line_no));
+            };
+            if value.is_empty() {
+                return Err(ZoneParseError::MissingField(line_no));
+            }
+
+            let record_type = parse_record_type(record_type)
+                .ok_or_else(|| ZoneParseError::UnsupportedType(line_no, record_type.to_string()))?;
+            let ttl = ttl
+                .parse()
+                .map_err(|_| ZoneParseError::InvalidTtl(line_no))?;
+            let data = Self::parse_rdata(record_type, &value.join(" "))
+                .ok_or(ZoneParseError::InvalidValue(line_no))?;
+
+            let key = Self::key(name);
+            let record = Answer::new(key.clone(), record_type, 1, ttl, data.len() as u16, data);
+            records.entry(key).or_default().push(record);
+        }
+
+        Ok(Self { records })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_distinguishes_missing_name_from_missing_type() {
+        let zone: Zone = "; test zone\nwww.example.com. A 300 192.0.2.10\n"
+            .parse()
+            .unwrap();
+
+        let answers = zone.lookup("WWW.example.com", 1).unwrap();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].name, "WWW.example.com");
+        assert_eq!(answers[0].time_to_live, 300);
+        assert_eq!(answers[0].data, [192, 0, 2, 10]);
+
+        assert_eq!(zone.lookup("www.example.com", 28).map(|a| a.len()), Some(0));
+        assert!(zone.lookup("example.com", 1).is_none());
+    }
+
+    #[test]
+    fn from_str_rejects_malformed_lines() {
+        assert_eq!(
+            "a.test A 60".parse::<Zone>().err(),
+            Some(ZoneParseError::MissingField(1))
+        );
+        assert_eq!(
+            "\na.test A sixty 192.0.2.1".parse::<Zone>().err(),
+            Some(ZoneParseError::InvalidTtl(2))
+        );
+        assert_eq!(
+            "a.test A 60 not-an-address".parse::<Zone>().err(),
+            Some(ZoneParseError::InvalidValue(1))
+        );
+    }
+}